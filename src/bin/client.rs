@@ -1,4 +1,7 @@
 use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::os::unix::{io::IntoRawFd, net::UnixStream};
 
 use clap::Parser;
 use color_eyre::{
@@ -6,7 +9,11 @@ use color_eyre::{
     Result,
 };
 use fork::{daemon, Fork};
-use nbd::{client::Client, kernel, proto::DEFAULT_PORT};
+use nbd::{
+    client::{Client, ClientOptions},
+    kernel,
+    proto::DEFAULT_PORT,
+};
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
@@ -17,6 +24,30 @@ struct Args {
     #[clap(short, long, default_value_t = DEFAULT_PORT)]
     port: u16,
 
+    #[clap(
+        long,
+        conflicts_with_all = ["host", "port"],
+        help = "connect to a server on a Unix socket instead of TCP"
+    )]
+    unix: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        conflicts_with_all = ["host", "port", "unix"],
+        help = "connect to the export in an NBD URI (for example, nbd://host/export)"
+    )]
+    uri: Option<String>,
+
+    #[clap(
+        short = 'N',
+        long = "name",
+        default_value = "",
+        conflicts_with = "uri",
+        help = "name of the export to use (the server's default export if empty)"
+    )]
+    export_name: String,
+
     #[clap(short, long, help = "disconnect from an existing client")]
     disconnect: bool,
 
@@ -51,9 +82,32 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let client = Client::connect(&args.host, args.port).wrap_err("connecting to nbd server")?;
+    if let Some(uri) = &args.uri {
+        let client = Client::connect_uri(uri).wrap_err("connecting to nbd server")?;
+        return attach(&args, client);
+    }
+    let opts = ClientOptions {
+        export_name: args.export_name.clone(),
+        ..Default::default()
+    };
+    match &args.unix {
+        Some(path) => {
+            let stream = UnixStream::connect(path).wrap_err("connecting to nbd server")?;
+            let client = Client::with_options(stream, &opts).wrap_err("nbd handshake failed")?;
+            attach(&args, client)
+        }
+        None => {
+            let stream = TcpStream::connect((args.host.as_str(), args.port))
+                .wrap_err("connecting to nbd server")?;
+            let client = Client::with_options(stream, &opts).wrap_err("nbd handshake failed")?;
+            attach(&args, client)
+        }
+    }
+}
 
-    let nbd = match open_nbd(&args) {
+/// Hand a connected client to the kernel and wait for it to disconnect.
+fn attach<IO: Read + Write + IntoRawFd>(args: &Args, client: Client<IO>) -> Result<()> {
+    let nbd = match open_nbd(args) {
         Ok(nbd) => nbd,
         Err(err) => {
             eprintln!("could not open nbd device - do you need to run sudo modprobe nbd?");
@@ -1,21 +1,178 @@
 use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
 
-use clap::{Parser, Subcommand};
-use color_eyre::Result;
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use color_eyre::{eyre::bail, Result};
+#[cfg(feature = "io-uring")]
+use nbd::uring::{Uring, UringOptions};
 use nbd::{
     proto::DEFAULT_PORT,
-    server::{Device, MemBlocks, Server},
+    server::{Blocks, Device, ExportOptions, Handshake, MemBlocks, Server, DEFAULT_EXPORT},
+    tls::{ServerTls, TlsPolicy},
 };
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
 struct Args {
+    /// Refuse writes from clients (files and devices are opened read-only)
+    #[arg(long)]
+    read_only: bool,
+
+    /// Handshake to greet clients with (older handshakes are only for old
+    /// clients)
+    #[arg(long, value_enum, default_value_t = HandshakeArg::FixedNewstyle)]
+    handshake: HandshakeArg,
+
+    /// Requests to handle at once on each connection (without TLS), which
+    /// the server may reply to out of order
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+
+    /// Read and write files and devices with io_uring
+    #[cfg(feature = "io-uring")]
+    #[arg(long)]
+    io_uring: bool,
+
+    #[command(flatten)]
+    listen: ListenArgs,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    #[command(subcommand)]
+    subcommand: Subcommands,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ListenArgs {
     /// The port the server should listen to
     #[arg(short, long, default_value_t = DEFAULT_PORT)]
     port: u16,
 
-    #[command(subcommand)]
-    subcommand: Subcommands,
+    /// Address to listen on, optionally with a port (for example, 0.0.0.0,
+    /// [::]:10810, or 192.168.1.2); can be given several times [default:
+    /// 127.0.0.1]
+    #[arg(short, long, value_parser = parse_bind)]
+    bind: Vec<(IpAddr, Option<u16>)>,
+
+    /// Listen on a Unix socket at this path instead of TCP
+    #[arg(long, conflicts_with_all = ["port", "bind"])]
+    unix: Option<String>,
+}
+
+fn parse_bind(s: &str) -> std::result::Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    // also allow IPv6 addresses in brackets without a port
+    let ip = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|_| format!("{s:?} is not an IP address"))?;
+    Ok((ip, None))
+}
+
+impl ListenArgs {
+    fn start(&self, server: Server) -> Result<()> {
+        if let Some(path) = &self.unix {
+            return server.start_unix(path);
+        }
+        if self.bind.is_empty() {
+            return server.start(self.port);
+        }
+        let addrs: Vec<SocketAddr> = self
+            .bind
+            .iter()
+            .map(|&(ip, port)| SocketAddr::new(ip, port.unwrap_or(self.port)))
+            .collect();
+        server.start_on(&addrs)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HandshakeArg {
+    /// Fixed newstyle negotiation
+    FixedNewstyle,
+    /// Also accept clients without fixed newstyle support
+    Newstyle,
+    /// Oldstyle negotiation (no TLS)
+    Oldstyle,
+}
+
+impl From<HandshakeArg> for Handshake {
+    fn from(arg: HandshakeArg) -> Self {
+        match arg {
+            HandshakeArg::FixedNewstyle => Handshake::FixedNewstyle,
+            HandshakeArg::Newstyle => Handshake::Newstyle,
+            HandshakeArg::Oldstyle => Handshake::Oldstyle,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct TlsArgs {
+    /// Certificate (PEM) to offer TLS with
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// Private key (PEM) for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// CA certificate (PEM) that client certificates must be signed by
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<String>,
+
+    /// PSK file (identity:hex-key lines) to offer TLS with, instead of a
+    /// certificate
+    #[arg(long, conflicts_with = "tls_cert")]
+    tls_psk: Option<String>,
+
+    /// Refuse clients that do not use TLS
+    #[arg(long)]
+    tls_required: bool,
+}
+
+impl TlsArgs {
+    fn server_tls(&self) -> Result<Option<ServerTls>> {
+        let tls = match (&self.tls_cert, &self.tls_key, &self.tls_psk) {
+            (Some(cert), Some(key), _) => Some(ServerTls::x509(cert, key, self.tls_ca.as_ref())?),
+            (_, _, Some(psk)) => Some(ServerTls::psk_file(psk)?),
+            _ => None,
+        };
+        if tls.is_none() && self.tls_required {
+            bail!("--tls-required needs --tls-cert or --tls-psk");
+        }
+        Ok(tls)
+    }
+}
+
+fn serve<F: Blocks + Sync + Send + 'static>(
+    blocks: F,
+    read_only: bool,
+    handshake: Handshake,
+    workers: usize,
+    tls: &TlsArgs,
+    listen: &ListenArgs,
+) -> Result<()> {
+    let server = Server::empty(tls.server_tls()?);
+    server.set_handshake(handshake);
+    server.set_workers(workers);
+    let policy = if tls.tls_required {
+        TlsPolicy::Required
+    } else {
+        TlsPolicy::Optional
+    };
+    let opts = ExportOptions {
+        tls: policy,
+        read_only,
+        ..Default::default()
+    };
+    server.add_export(DEFAULT_EXPORT, blocks, opts)?;
+    listen.start(server)
 }
 
 const DEFAULT_SIZE: u64 = 10 * 1024 * 1024;
@@ -30,7 +187,7 @@ enum Subcommands {
     },
     /// Spawn a server backed by a file
     File {
-        /// Size of backing storage
+        /// Size of backing storage (ignored with --read-only)
         #[arg(short, long, default_value_t = DEFAULT_SIZE)]
         size: u64,
 
@@ -52,35 +209,58 @@ fn main() -> Result<()> {
     color_eyre::install()?;
     env_logger::init();
 
-    let Args { port, subcommand } = Args::parse();
+    let Args {
+        listen,
+        read_only,
+        handshake,
+        workers,
+        #[cfg(feature = "io-uring")]
+        io_uring,
+        tls,
+        subcommand,
+    } = Args::parse();
+    let handshake = Handshake::from(handshake);
 
     match subcommand {
         Subcommands::Memory { size } => {
             let data = vec![0; size as usize];
             let export = MemBlocks::new(data);
-            Server::new(export).start(port)?;
+            serve(export, read_only, handshake, workers, &tls, &listen)?;
         }
         Subcommands::File {
             size,
             no_create,
             path,
         } => {
-            let file = File::options()
-                .read(true)
-                .write(true)
-                .create(!no_create)
-                .truncate(!no_create)
-                .open(&path)?;
-
-            file.set_len(size)?;
+            let file = if read_only {
+                // export the file as it is
+                File::open(&path)?
+            } else {
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(!no_create)
+                    .truncate(!no_create)
+                    .open(&path)?;
+                file.set_len(size)?;
+                file
+            };
 
-            Server::new(file).start(port)?;
+            #[cfg(feature = "io-uring")]
+            if io_uring {
+                let file = Uring::file(file, &UringOptions::default())?;
+                return serve(file, read_only, handshake, workers, &tls, &listen);
+            }
+            serve(file, read_only, handshake, workers, &tls, &listen)?;
         }
         Subcommands::Device { path } => {
-            Server::new(Device::new(
-                File::options().read(true).write(true).open(&path)?,
-            ))
-            .start(port)?;
+            let device = Device::open(&path, read_only)?;
+            #[cfg(feature = "io-uring")]
+            if io_uring {
+                let device = Uring::device(device, &UringOptions::default())?;
+                return serve(device, read_only, handshake, workers, &tls, &listen);
+            }
+            serve(device, read_only, handshake, workers, &tls, &listen)?;
         }
     }
 
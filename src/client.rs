@@ -3,83 +3,815 @@
 //! See the documentation for [`Client`].
 #![deny(missing_docs)]
 
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, ensure, eyre, WrapErr};
 use color_eyre::Result;
+use log::warn;
 
 use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
     io::prelude::*,
     net::TcpStream,
-    os::unix::io::{IntoRawFd, RawFd},
+    ops::Range,
+    os::unix::{
+        io::{IntoRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::Path,
 };
 
-use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use byteorder::{ReadBytesExt, BE};
+use openssl::ssl::SslStream;
 
+#[cfg(feature = "tokio")]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+#[cfg(feature = "tokio")]
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+#[cfg(feature = "tokio")]
+use crate::connection::Tokio;
+use crate::connection::{block_on, Blocking, Connection, ReadMessages};
 use crate::proto::*;
+use crate::tls::ClientTls;
+use crate::uri::{NbdUri, Transport};
 
 #[derive(Debug)]
 struct Export {
     size: u64,
+    flags: TransmitFlags,
+    // the server's canonical name for the export
+    name: Option<String>,
+    description: Option<String>,
+    block_sizes: Option<BlockSizes>,
+}
+
+impl Export {
+    /// Check that a request meets the block size constraints the server sent,
+    /// which the client promised to follow by asking for them.
+    fn check_block_sizes(&self, req: &Request) -> Result<()> {
+        let Some(sizes) = self.block_sizes else {
+            return Ok(());
+        };
+        ensure!(
+            sizes.aligned(req.offset, req.len),
+            "{:?} of {} bytes at offset {} is not aligned to the minimum block size {}",
+            req.typ,
+            req.len,
+            req.offset,
+            sizes.minimum
+        );
+        if matches!(req.typ, Cmd::READ | Cmd::WRITE) {
+            ensure!(
+                req.len <= sizes.maximum as u64,
+                "{:?} of {} bytes is larger than the maximum block size {}",
+                req.typ,
+                req.len,
+                sizes.maximum
+            );
+        }
+        Ok(())
+    }
+}
+
+/// An export offered by a server, from [`Client::list_exports`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportEntry {
+    /// Name to select the export with ([`ClientOptions::export_name`]).
+    pub name: String,
+    /// Human-readable description, if the server has one.
+    pub description: Option<String>,
+}
+
+/// The server refused a request during the handshake, such as using or
+/// listing exports.
+///
+/// Errors from [`Client::new`] and [`Client::with_options`] can be downcast to
+/// this type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NegotiationError {
+    /// The export does not exist (`NBD_REP_ERR_UNKNOWN`).
+    UnknownExport(String),
+    /// The server's policy does not allow using the export
+    /// (`NBD_REP_ERR_POLICY`).
+    Policy(String),
+    /// The export can only be used over TLS (`NBD_REP_ERR_TLS_REQD`).
+    TlsRequired(String),
+    /// The server refused for another reason, with the raw reply type.
+    Other {
+        /// The `NBD_REP_ERR_*` reply type.
+        reply: u32,
+        /// The server's explanation, if any.
+        message: String,
+    },
+}
+
+impl NegotiationError {
+    fn new(reply: ReplyType, message: String) -> Self {
+        match reply {
+            ReplyType::ERR_UNKNOWN => Self::UnknownExport(message),
+            ReplyType::ERR_POLICY => Self::Policy(message),
+            ReplyType::ERR_TLS_REQD => Self::TlsRequired(message),
+            _ => Self::Other {
+                reply: reply.into(),
+                message,
+            },
+        }
+    }
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::UnknownExport(message) => {
+                write!(f, "unknown export")?;
+                message
+            }
+            Self::Policy(message) => {
+                write!(f, "export not allowed by server policy")?;
+                message
+            }
+            Self::TlsRequired(message) => {
+                write!(f, "export requires TLS")?;
+                message
+            }
+            Self::Other { reply, message } => {
+                write!(f, "server refused with error {reply:#x}")?;
+                message
+            }
+        };
+        if !message.is_empty() {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NegotiationError {}
+
+/// Options the client requests from the server during the handshake.
+///
+/// The defaults only use features the Linux kernel supports, so that the
+/// resulting [`Client`] can be passed to [`crate::kernel::set_client`].
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// Name of the export to use. The empty name selects the server's default
+    /// export.
+    pub export_name: String,
+    /// Request structured replies (`NBD_OPT_STRUCTURED_REPLY`), which allow
+    /// sparse reads and reporting errors for part of a read.
+    pub structured_replies: bool,
+    /// Request extended headers (`NBD_OPT_EXTENDED_HEADERS`), which allow
+    /// 64-bit request lengths. Extended headers imply structured replies.
+    pub extended_headers: bool,
+    /// Metadata contexts to select for [`Client::block_status`] (for example,
+    /// [`BASE_ALLOCATION`]). Requires structured replies.
+    pub meta_contexts: Vec<String>,
+    /// Upgrade the connection to TLS (`NBD_OPT_STARTTLS`) before negotiating
+    /// anything else. The handshake fails if the server does not support
+    /// TLS.
+    pub tls: Option<ClientTls>,
+    /// Also accept servers that only support oldstyle or non-fixed newstyle
+    /// negotiation, or that pad the export information with zeroes. Old
+    /// servers may ignore the options above.
+    pub legacy: bool,
+}
+
+/// How the server started the handshake.
+#[derive(Debug)]
+enum Greeting {
+    /// Newstyle negotiation, where the client sends options.
+    Newstyle { fixed: bool, no_zeroes: bool },
+    /// Oldstyle negotiation, where the server immediately sends the export
+    /// information.
+    Oldstyle(Export),
+}
+
+/// Features the server agreed to during the handshake.
+#[derive(Debug, Default, Clone)]
+struct Negotiated {
+    structured_replies: bool,
+    extended_headers: bool,
+    // names of selected contexts, by context ID
+    meta_contexts: BTreeMap<u32, String>,
+}
+
+impl Negotiated {
+    /// Name the extents of a block status reply by their metadata context.
+    fn block_status(&self, done: Done) -> Result<BTreeMap<String, Vec<Extent>>> {
+        let mut status = BTreeMap::new();
+        for (id, extents) in done.status {
+            let name = self.meta_contexts.get(&id).ok_or_else(|| {
+                ProtocolError::new(format!("block status for unknown context {id}"))
+            })?;
+            status.insert(name.clone(), extents);
+        }
+        Ok(status)
+    }
+}
+
+/// Identifies a request submitted with [`Client::submit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle(u64);
+
+/// A command that can be submitted without waiting for its reply (see
+/// [`Client::submit`]).
+#[derive(Debug, Clone, Copy)]
+pub enum Command<'a> {
+    /// Read `len` bytes starting at `offset`.
+    Read {
+        /// Start of the read.
+        offset: u64,
+        /// Number of bytes to read.
+        len: u32,
+    },
+    /// Write `data` starting at `offset`.
+    Write {
+        /// Start of the write.
+        offset: u64,
+        /// Data to write, which is sent when the command is submitted.
+        data: &'a [u8],
+    },
+    /// Write zeroes (see [`Client::write_zeroes`]).
+    WriteZeroes {
+        /// Start of the range.
+        offset: u64,
+        /// Length of the range.
+        len: u64,
+        /// Do not deallocate the range.
+        no_hole: bool,
+        /// Fail rather than zero the range slowly.
+        fast: bool,
+    },
+    /// Discard a range (see [`Client::trim`]).
+    Trim {
+        /// Start of the range.
+        offset: u64,
+        /// Length of the range.
+        len: u64,
+    },
+    /// Prefetch a range (see [`Client::cache`]).
+    Cache {
+        /// Start of the range.
+        offset: u64,
+        /// Length of the range.
+        len: u64,
+    },
+    /// Flush all completed writes to stable storage.
+    Flush,
+}
+
+impl<'a> Command<'a> {
+    fn into_request(self) -> (Request, &'a [u8]) {
+        match self {
+            Command::Read { offset, len } => (Request::new(Cmd::READ, offset, len as u64), &[]),
+            Command::Write { offset, data } => {
+                (Request::new(Cmd::WRITE, offset, data.len() as u64), data)
+            }
+            Command::WriteZeroes {
+                offset,
+                len,
+                no_hole,
+                fast,
+            } => {
+                let mut req = Request::new(Cmd::WRITE_ZEROES, offset, len);
+                if no_hole {
+                    req.flags |= CmdFlags::NO_HOLE;
+                }
+                if fast {
+                    req.flags |= CmdFlags::FAST_ZERO;
+                }
+                (req, &[])
+            }
+            Command::Trim { offset, len } => (Request::new(Cmd::TRIM, offset, len), &[]),
+            Command::Cache { offset, len } => (Request::new(Cmd::CACHE, offset, len), &[]),
+            Command::Flush => (Request::new(Cmd::FLUSH, 0, 0), &[]),
+        }
+    }
+}
+
+/// A request whose reply has been received, from [`Client::complete`].
+#[derive(Debug)]
+pub struct Completion {
+    /// The handle [`Client::submit`] returned for the request.
+    pub handle: Handle,
+    /// The data for a [`Command::Read`] (empty for other commands), or the
+    /// error the server reported.
+    pub result: Result<Vec<u8>>,
+}
+
+/// Room in a reply chunk beyond the data of a read, for the chunk's own
+/// fields, an error message (at most 4096 bytes), or block status descriptors.
+const MAX_CHUNK_OVERHEAD: usize = 16 * 1024 * 1024;
+
+/// A request that has been sent, with the parts of its reply received so far.
+#[derive(Debug)]
+struct InFlight {
+    req: Request,
+    buf: Vec<u8>,
+    // extents from block status chunks, by context ID
+    status: Vec<(u32, Vec<Extent>)>,
+    // the first error chunk
+    error: Option<String>,
+}
+
+/// The result of a successful request.
+#[derive(Debug)]
+struct Done {
+    buf: Vec<u8>,
+    status: Vec<(u32, Vec<Extent>)>,
+}
+
+impl InFlight {
+    fn new(req: Request) -> Self {
+        let buf_len = if req.typ == Cmd::READ { req.len } else { 0 };
+        Self {
+            req,
+            buf: vec![0; buf_len as usize],
+            status: vec![],
+            error: None,
+        }
+    }
+
+    /// Get the range of the read buffer covered by a chunk of a structured
+    /// reply.
+    fn chunk_range(&self, offset: u64, len: usize) -> Result<Range<usize>> {
+        let start = offset
+            .checked_sub(self.req.offset)
+            .map(|start| start as usize);
+        match start {
+            Some(start) if start + len <= self.buf.len() => Ok(start..start + len),
+            _ => bail!(ProtocolError::new(format!(
+                "reply chunk at offset {offset} of length {len} is outside of request"
+            ))),
+        }
+    }
+
+    /// Return the longest reply payload the server may send for this request.
+    fn max_payload(&self) -> usize {
+        self.buf.len() + MAX_CHUNK_OVERHEAD
+    }
+
+    /// Add a chunk of a structured reply, returning whether it was the last
+    /// one.
+    fn add_chunk(&mut self, chunk: ReplyChunk) -> Result<bool> {
+        let done = chunk.is_done();
+        match chunk.payload {
+            ChunkPayload::None => {}
+            ChunkPayload::OffsetData { offset, data } => {
+                let range = self.chunk_range(offset, data.len())?;
+                self.buf[range].copy_from_slice(&data);
+            }
+            ChunkPayload::OffsetHole { offset, len } => {
+                let range = self.chunk_range(offset, len as usize)?;
+                self.buf[range].fill(0);
+            }
+            ChunkPayload::BlockStatus {
+                context_id,
+                extents,
+            } => {
+                self.status.push((context_id, extents));
+            }
+            ChunkPayload::Error { err, msg, offset } => {
+                // report the first error, but keep reading the remaining chunks
+                if self.error.is_none() {
+                    let typ = self.req.typ;
+                    self.error = Some(match offset {
+                        Some(offset) => format!("{typ:?} failed at offset {offset}: {err:?} {msg}"),
+                        None => format!("{typ:?} failed: {err:?} {msg}"),
+                    });
+                }
+            }
+        }
+        Ok(done)
+    }
+
+    /// Record the error from a simple reply.
+    fn fail(&mut self, err: ErrorType) {
+        self.error = Some(format!("{:?} failed: {:?}", self.req.typ, err));
+    }
+
+    /// Return the result of the request, once its whole reply is received.
+    fn finish(self) -> Result<Done> {
+        match self.error {
+            Some(error) => Err(eyre!(error)),
+            None => Ok(Done {
+                buf: self.buf,
+                status: self.status,
+            }),
+        }
+    }
+}
+
+/// The connection to the server, possibly upgraded to TLS.
+#[derive(Debug)]
+enum Conn<IO: Read + Write> {
+    Plain(IO),
+    Tls(Box<SslStream<IO>>),
+}
+
+impl<IO: Read + Write> Read for Conn<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Plain(stream) => stream.read(buf),
+            Conn::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<IO: Read + Write> Write for Conn<IO> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Plain(stream) => stream.write(buf),
+            Conn::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Conn::Plain(stream) => stream.flush(),
+            Conn::Tls(stream) => stream.flush(),
+        }
+    }
 }
 
 /// Client provides an interface to an export from a remote NBD server.
+///
+/// Methods such as [`Client::read`] wait for their reply. To have many
+/// requests in flight, use [`Client::submit`] and [`Client::complete`].
 #[derive(Debug)]
 pub struct Client<IO: Read + Write> {
-    conn: IO,
+    conn: Conn<IO>,
     export: Export,
+    negotiated: Negotiated,
+    in_flight: BTreeMap<u64, InFlight>,
+    // requests that completed while waiting for another one
+    completed: VecDeque<Completion>,
+    next_handle: u64,
 }
 
-impl<IO: Read + Write> Client<IO> {
-    fn initial_handshake(stream: &mut (impl Read + Write)) -> Result<()> {
-        let magic = stream.read_u64::<BE>()?;
-        if magic != MAGIC {
-            bail!(ProtocolError::new(format!("unexpected magic {}", magic)));
-        }
-        let opt_magic = stream.read_u64::<BE>()?;
-        if opt_magic != IHAVEOPT {
-            bail!(ProtocolError::new(format!(
-                "unexpected IHAVEOPT value {opt_magic}",
-            )))
-        }
-        let server_flags = stream.read_u16::<BE>()?;
-        let server_flags = HandshakeFlags::from_bits(server_flags)
-            .ok_or_else(|| ProtocolError::new(format!("unexpected server flags {server_flags}")))?;
-        if !server_flags.contains(HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES) {
-            bail!(ProtocolError::new("server does not support NO_ZEROES"));
-        }
-        let client_flags =
-            ClientHandshakeFlags::C_FIXED_NEWSTYLE | ClientHandshakeFlags::C_NO_ZEROES;
-        stream.write_u32::<BE>(client_flags.bits())?;
-        Ok(())
+async fn initial_handshake<C: Connection>(conn: &mut C, legacy: bool) -> Result<Greeting> {
+    let mut header = [0u8; 16];
+    conn.read_exact(&mut header).await?;
+    let mut header = &header[..];
+    let magic = header.read_u64::<BE>()?;
+    if magic != MAGIC {
+        bail!(ProtocolError::new(format!("unexpected magic {}", magic)));
+    }
+    let opt_magic = header.read_u64::<BE>()?;
+    if legacy && opt_magic == CLISERV_MAGIC {
+        // S: 64 bits, size of the export in bytes (unsigned)
+        // S: 32 bits, flags (the handshake flags are the upper 16 bits)
+        // S: 124 bytes, zeroes (reserved)
+        let mut info = [0u8; 8 + 4 + 124];
+        conn.read_exact(&mut info).await?;
+        let mut info = &info[..];
+        let size = info.read_u64::<BE>()?;
+        let flags = info.read_u32::<BE>()?;
+        let export = read_export(size, flags as u16)?;
+        return Ok(Greeting::Oldstyle(export));
+    }
+    if opt_magic != IHAVEOPT {
+        bail!(ProtocolError::new(format!(
+            "unexpected IHAVEOPT value {opt_magic}",
+        )))
+    }
+    let mut server_flags = [0u8; 2];
+    conn.read_exact(&mut server_flags).await?;
+    let server_flags = u16::from_be_bytes(server_flags);
+    let server_flags = HandshakeFlags::from_bits(server_flags)
+        .ok_or_else(|| ProtocolError::new(format!("unexpected server flags {server_flags}")))?;
+    if !legacy && !server_flags.contains(HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES)
+    {
+        bail!(ProtocolError::new("server does not support NO_ZEROES"));
+    }
+    let fixed = server_flags.contains(HandshakeFlags::FIXED_NEWSTYLE);
+    let no_zeroes = server_flags.contains(HandshakeFlags::NO_ZEROES);
+    let mut client_flags = ClientHandshakeFlags::empty();
+    if fixed {
+        client_flags |= ClientHandshakeFlags::C_FIXED_NEWSTYLE;
+    }
+    if no_zeroes {
+        client_flags |= ClientHandshakeFlags::C_NO_ZEROES;
+    }
+    conn.write_all(&client_flags.bits().to_be_bytes()).await?;
+    Ok(Greeting::Newstyle { fixed, no_zeroes })
+}
+
+fn read_export(size: u64, transmit_flags: u16) -> Result<Export> {
+    let transmit_flags = TransmitFlags::from_bits(transmit_flags)
+        .ok_or_else(|| ProtocolError::new(format!("invalid transmit flags {transmit_flags}")))?;
+    Ok(Export {
+        size,
+        flags: transmit_flags,
+        name: None,
+        description: None,
+        block_sizes: None,
+    })
+}
+
+/// Read the reply to `NBD_OPT_EXPORT_NAME`.
+async fn get_export_info<C: Connection>(conn: &mut C, no_zeroes: bool) -> Result<Export> {
+    let mut info = [0u8; 8 + 2 + 124];
+    let info_len = if no_zeroes { 8 + 2 } else { info.len() };
+    conn.read_exact(&mut info[..info_len]).await?;
+    let mut info = &info[..];
+    let size = info.read_u64::<BE>()?;
+    let transmit_flags = info.read_u16::<BE>()?;
+    read_export(size, transmit_flags)
+}
+
+/// Select an export with `NBD_OPT_EXPORT_NAME`, which ends the handshake.
+async fn select_export<C: Connection>(
+    conn: &mut C,
+    export_name: &str,
+    no_zeroes: bool,
+) -> Result<Export> {
+    conn.put_opt(Opt {
+        typ: OptType::EXPORT_NAME,
+        data: export_name.as_bytes().to_vec(),
+    })
+    .await?;
+    get_export_info(conn, no_zeroes).await
+}
+
+/// Send an option that expects a single ACK, returning false if the server
+/// refused it.
+async fn request_opt<C: Connection>(conn: &mut C, opt: Opt) -> Result<bool> {
+    let typ = opt.typ;
+    conn.put_opt(opt).await?;
+    let reply = conn.get_opt_reply().await?;
+    ensure!(
+        reply.opt == typ,
+        ProtocolError::new(format!("got reply to {:?} instead of {typ:?}", reply.opt))
+    );
+    if reply.reply_type != ReplyType::ACK {
+        warn!("server refused {typ:?}: {:?}", reply.reply_type);
+        return Ok(false);
     }
+    Ok(true)
+}
 
-    fn get_export_info(stream: &mut impl Read) -> Result<(Export, TransmitFlags)> {
-        let size = stream.read_u64::<BE>()?;
-        let transmit_flags = stream.read_u16::<BE>()?;
-        let transmit_flags = TransmitFlags::from_bits(transmit_flags)
-            .ok_or_else(|| ProtocolError::new("invalid transmit flags {transmit_flags}"))?;
-        let export = Export { size };
-        Ok((export, transmit_flags))
+async fn set_meta_contexts<C: Connection>(
+    conn: &mut C,
+    export_name: &str,
+    queries: &[String],
+) -> Result<BTreeMap<u32, String>> {
+    let mut data = vec![];
+    MetaContextRequest {
+        name: export_name.to_string(),
+        queries: queries.to_vec(),
+    }
+    .put(&mut data)?;
+    conn.put_opt(Opt {
+        typ: OptType::SET_META_CONTEXT,
+        data,
+    })
+    .await?;
+    let mut contexts = BTreeMap::new();
+    loop {
+        let reply = conn.get_opt_reply().await?;
+        match reply.reply_type {
+            ReplyType::META_CONTEXT => {
+                let mut data = &reply.data[..];
+                let id = data.read_u32::<BE>()?;
+                let name = String::from_utf8_lossy(data).to_string();
+                contexts.insert(id, name);
+            }
+            ReplyType::ACK => return Ok(contexts),
+            err => {
+                warn!("server refused metadata contexts: {err:?}");
+                return Ok(BTreeMap::new());
+            }
+        }
     }
+}
 
-    fn handshake_haggle(stream: &mut (impl Read + Write)) -> Result<Export> {
-        Opt {
-            typ: OptType::EXPORT_NAME,
-            data: b"default".to_vec(),
+/// Select an export with `NBD_OPT_GO`, which ends the handshake.
+///
+/// Returns None if the server does not support `NBD_OPT_GO`, in which case
+/// the handshake can continue.
+async fn go<C: Connection>(conn: &mut C, export_name: &str) -> Result<Option<Export>> {
+    let mut data = vec![];
+    InfoRequest {
+        name: export_name.to_string(),
+        typs: vec![InfoType::NAME, InfoType::DESCRIPTION, InfoType::BLOCK_SIZE],
+    }
+    .put(&mut data)?;
+    conn.put_opt(Opt {
+        typ: OptType::GO,
+        data,
+    })
+    .await?;
+    let mut export = None;
+    let (mut name, mut description, mut block_sizes) = (None, None, None);
+    loop {
+        let reply = conn.get_opt_reply().await?;
+        ensure!(
+            reply.opt == OptType::GO,
+            ProtocolError::new(format!("got reply to {:?} instead of GO", reply.opt))
+        );
+        match reply.reply_type {
+            ReplyType::INFO => match Info::get(&reply.data)? {
+                Some(Info::Export { size, flags }) => export = Some(read_export(size, flags)?),
+                Some(Info::Name(n)) => name = Some(n),
+                Some(Info::Description(d)) => description = Some(d),
+                Some(Info::BlockSize(sizes)) => {
+                    sizes
+                        .validate_protocol()
+                        .wrap_err(ProtocolError::new("invalid block sizes"))?;
+                    block_sizes = Some(sizes);
+                }
+                None => {}
+            },
+            ReplyType::ACK => {
+                let mut export = export
+                    .ok_or_else(|| ProtocolError::new("server did not send export information"))?;
+                export.name = name;
+                export.description = description;
+                export.block_sizes = block_sizes;
+                return Ok(Some(export));
+            }
+            ReplyType::ERR_UNSUP => {
+                warn!("server does not support GO");
+                return Ok(None);
+            }
+            err => {
+                let message = String::from_utf8_lossy(&reply.data).to_string();
+                bail!(NegotiationError::new(err, message));
+            }
         }
-        .put(stream)?;
-        // ignore transmit flags for now (we don't send anything fancy anyway)
-        let (export, _transmit_flags) = Self::get_export_info(stream)?;
-        Ok(export)
     }
+}
 
+/// Ask to upgrade the connection to TLS, failing if the server refuses.
+async fn request_tls<C: Connection>(conn: &mut C) -> Result<()> {
+    // continuing without TLS would silently give up on the security the
+    // caller asked for
+    let opt = Opt {
+        typ: OptType::STARTTLS,
+        data: vec![],
+    };
+    ensure!(request_opt(conn, opt).await?, "server does not support TLS");
+    Ok(())
+}
+
+async fn handshake_haggle<C: Connection>(
+    conn: &mut C,
+    opts: &ClientOptions,
+    no_zeroes: bool,
+) -> Result<(Export, Negotiated)> {
+    let mut negotiated = Negotiated::default();
+    if opts.extended_headers
+        && request_opt(
+            conn,
+            Opt {
+                typ: OptType::EXTENDED_HEADERS,
+                data: vec![],
+            },
+        )
+        .await?
+    {
+        negotiated.extended_headers = true;
+        negotiated.structured_replies = true;
+    }
+    if opts.structured_replies && !negotiated.structured_replies {
+        negotiated.structured_replies = request_opt(
+            conn,
+            Opt {
+                typ: OptType::STRUCTURED_REPLY,
+                data: vec![],
+            },
+        )
+        .await?;
+    }
+    if !opts.meta_contexts.is_empty() && negotiated.structured_replies {
+        negotiated.meta_contexts =
+            set_meta_contexts(conn, &opts.export_name, &opts.meta_contexts).await?;
+    }
+    let export = match go(conn, &opts.export_name).await? {
+        Some(export) => export,
+        None => select_export(conn, &opts.export_name, no_zeroes).await?,
+    };
+    Ok((export, negotiated))
+}
+
+/// List the exports a server offers, and then abort the handshake.
+async fn get_export_list<C: Connection>(conn: &mut C) -> Result<Vec<ExportEntry>> {
+    conn.put_opt(Opt {
+        typ: OptType::LIST,
+        data: vec![],
+    })
+    .await?;
+    let mut exports = vec![];
+    loop {
+        let reply = conn.get_opt_reply().await?;
+        ensure!(
+            reply.opt == OptType::LIST,
+            ProtocolError::new(format!("got reply to {:?} instead of LIST", reply.opt))
+        );
+        match reply.reply_type {
+            ReplyType::SERVER => {
+                let (name, description) = ExportList::get_entry(&reply.data)?;
+                exports.push(ExportEntry { name, description });
+            }
+            ReplyType::ACK => break,
+            err => {
+                let message = String::from_utf8_lossy(&reply.data).to_string();
+                bail!(NegotiationError::new(err, message));
+            }
+        }
+    }
+    // the server may acknowledge the abort, but there is no need to wait
+    conn.put_opt(Opt {
+        typ: OptType::ABORT,
+        data: vec![],
+    })
+    .await?;
+    Ok(exports)
+}
+
+impl<IO: Read + Write> Client<IO> {
     /// Establish a handshake with stream and return a `Client` ready for use.
-    pub fn new(mut stream: IO) -> Result<Self> {
-        Self::initial_handshake(&mut stream)?;
-        let export = Self::handshake_haggle(&mut stream)?;
-        Ok(Self {
-            conn: stream,
+    pub fn new(stream: IO) -> Result<Self> {
+        Self::with_options(stream, &ClientOptions::default())
+    }
+
+    /// Establish a handshake with stream, requesting the features in `opts`.
+    pub fn with_options(stream: IO, opts: &ClientOptions) -> Result<Self> {
+        let mut stream = Blocking(stream);
+        let (fixed, no_zeroes) = match block_on(initial_handshake(&mut stream, opts.legacy))? {
+            Greeting::Newstyle { fixed, no_zeroes } => (fixed, no_zeroes),
+            Greeting::Oldstyle(export) => {
+                ensure!(opts.tls.is_none(), "server does not support TLS");
+                return Ok(Self::ready(
+                    Conn::Plain(stream.0),
+                    export,
+                    Negotiated::default(),
+                ));
+            }
+        };
+        if !fixed {
+            // options other than NBD_OPT_EXPORT_NAME cannot be refused without
+            // closing the connection
+            ensure!(opts.tls.is_none(), "server does not support TLS");
+            let export = block_on(select_export(&mut stream, &opts.export_name, no_zeroes))?;
+            return Ok(Self::ready(
+                Conn::Plain(stream.0),
+                export,
+                Negotiated::default(),
+            ));
+        }
+        let mut conn = Blocking(Self::start_tls(stream, opts)?);
+        let (export, negotiated) = block_on(handshake_haggle(&mut conn, opts, no_zeroes))?;
+        Ok(Self::ready(conn.0, export, negotiated))
+    }
+
+    /// Create a client for the transmission phase.
+    fn ready(conn: Conn<IO>, export: Export, negotiated: Negotiated) -> Self {
+        Self {
+            conn,
             export,
-        })
+            negotiated,
+            in_flight: BTreeMap::new(),
+            completed: VecDeque::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Upgrade the connection to TLS if `opts` ask for it.
+    fn start_tls(mut stream: Blocking<IO>, opts: &ClientOptions) -> Result<Conn<IO>> {
+        let conn = match &opts.tls {
+            Some(tls) => {
+                block_on(request_tls(&mut stream))?;
+                Conn::Tls(Box::new(tls.connect(stream.0)?))
+            }
+            None => Conn::Plain(stream.0),
+        };
+        Ok(conn)
+    }
+
+    /// List the exports a server offers, and end the handshake without
+    /// using any.
+    ///
+    /// Only `tls` and `legacy` from `opts` apply. Servers may leave out
+    /// exports that require TLS if the connection does not use it.
+    pub fn list_exports(stream: IO, opts: &ClientOptions) -> Result<Vec<ExportEntry>> {
+        let mut stream = Blocking(stream);
+        let fixed = match block_on(initial_handshake(&mut stream, opts.legacy))? {
+            Greeting::Newstyle { fixed, .. } => fixed,
+            Greeting::Oldstyle(_) => bail!("oldstyle servers cannot list exports"),
+        };
+        ensure!(fixed || opts.tls.is_none(), "server does not support TLS");
+        let mut conn = Blocking(Self::start_tls(stream, opts)?);
+        block_on(get_export_list(&mut conn))
     }
 
     /// Return the size of this export, as reported by the server during the
@@ -88,52 +820,237 @@ impl<IO: Read + Write> Client<IO> {
         self.export.size
     }
 
-    fn get_reply_data(&mut self, req: &Request, buf: &mut [u8]) -> Result<()> {
-        let reply = SimpleReply::get(&mut self.conn, buf)?;
-        if reply.handle != req.handle {
-            bail!(format!(
-                "reply for wrong handle {} != {}",
-                reply.handle, req.handle
-            ))
+    /// Return the transmission flags the server sent for the export, which
+    /// say which commands it supports.
+    pub fn transmit_flags(&self) -> TransmitFlags {
+        self.export.flags
+    }
+
+    /// Return the block size constraints the server sent for the export.
+    ///
+    /// Servers that do not send any (or that do not support `NBD_OPT_GO`)
+    /// accept requests of any alignment, so this returns the defaults.
+    /// Requests that do not meet the constraints fail without being sent.
+    pub fn block_sizes(&self) -> BlockSizes {
+        self.export.block_sizes.unwrap_or_default()
+    }
+
+    /// Return the server's canonical name for the export, if it reported one.
+    pub fn export_name(&self) -> Option<&str> {
+        self.export.name.as_deref()
+    }
+
+    /// Return the server's human-readable description of the export, if it
+    /// has one.
+    pub fn description(&self) -> Option<&str> {
+        self.export.description.as_deref()
+    }
+
+    /// Return whether the server agreed to send structured replies.
+    pub fn structured_replies(&self) -> bool {
+        self.negotiated.structured_replies
+    }
+
+    /// Return whether the connection uses TLS.
+    pub fn tls(&self) -> bool {
+        matches!(self.conn, Conn::Tls(_))
+    }
+
+    /// Return whether the server agreed to use extended headers.
+    pub fn extended_headers(&self) -> bool {
+        self.negotiated.extended_headers
+    }
+
+    /// Return the names of the metadata contexts the server selected.
+    pub fn meta_contexts(&self) -> Vec<&str> {
+        self.negotiated
+            .meta_contexts
+            .values()
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    /// Send a request without waiting for its reply.
+    fn send(&mut self, mut req: Request, data: &[u8]) -> Result<Handle> {
+        self.export.check_block_sizes(&req)?;
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        req.handle = handle;
+        req.put(data, &mut self.conn, self.negotiated.extended_headers)?;
+        self.in_flight.insert(req.handle, InFlight::new(req));
+        Ok(Handle(handle))
+    }
+
+    /// Receive replies until some request completes, and return its result.
+    ///
+    /// Errors in the outer result are fatal for the connection, while the
+    /// inner result is the request's own.
+    fn receive(&mut self) -> Result<(Handle, Result<Done>)> {
+        loop {
+            let in_flight = &self.in_flight;
+            let max_payload = |header: &ReplyHeader| match in_flight.get(&header.handle()) {
+                Some(pending) => Ok(pending.max_payload()),
+                None => bail!(ProtocolError::new(format!(
+                    "reply for unknown handle {}",
+                    header.handle()
+                ))),
+            };
+            let reply = block_on(Blocking(&mut self.conn).get_reply(max_payload))?;
+            let handle = reply.handle();
+            let pending = self.in_flight.get_mut(&handle).unwrap();
+            let done = match reply {
+                Reply::Simple { err, .. } => {
+                    if err == ErrorType::OK {
+                        self.conn.read_exact(&mut pending.buf)?;
+                    } else {
+                        pending.fail(err);
+                    }
+                    true
+                }
+                Reply::Chunk(chunk) => pending.add_chunk(chunk)?,
+            };
+            if done {
+                let pending = self.in_flight.remove(&handle).unwrap();
+                return Ok((Handle(handle), pending.finish()));
+            }
         }
-        if reply.err != ErrorType::OK {
-            bail!(format!("{:?} failed: {:?}", req.typ, reply.err))
+    }
+
+    /// Wait for the reply to a request, setting aside other requests that
+    /// complete in the meantime.
+    fn wait(&mut self, handle: Handle) -> Result<Done> {
+        loop {
+            let (done, result) = self.receive()?;
+            if done == handle {
+                return result;
+            }
+            self.completed.push_back(Completion {
+                handle: done,
+                result: result.map(|done| done.buf),
+            });
         }
-        Ok(())
     }
 
-    fn get_ack(&mut self, req: &Request) -> Result<()> {
-        self.get_reply_data(req, &mut [])
+    /// Send a request and wait for its reply.
+    fn request(&mut self, req: Request, data: &[u8]) -> Result<Done> {
+        let handle = self.send(req, data)?;
+        self.wait(handle)
+    }
+
+    /// Send a command and wait for its reply.
+    fn run(&mut self, cmd: Command) -> Result<Done> {
+        let (req, data) = cmd.into_request();
+        self.request(req, data)
+    }
+
+    /// Send a command without waiting for its reply, which
+    /// [`Client::complete`] returns later.
+    ///
+    /// Requests may complete in any order. The server might stop reading
+    /// requests while its replies are not being received, so callers should
+    /// bound the number of requests in flight (see [`Client::in_flight`]).
+    pub fn submit(&mut self, cmd: Command) -> Result<Handle> {
+        let (req, data) = cmd.into_request();
+        self.send(req, data)
+    }
+
+    /// Wait for any submitted request to complete.
+    ///
+    /// An error means the connection failed, while errors for the request
+    /// itself are in [`Completion::result`].
+    pub fn complete(&mut self) -> Result<Completion> {
+        if let Some(completion) = self.completed.pop_front() {
+            return Ok(completion);
+        }
+        ensure!(!self.in_flight.is_empty(), "no requests in flight");
+        let (handle, result) = self.receive()?;
+        Ok(Completion {
+            handle,
+            result: result.map(|done| done.buf),
+        })
+    }
+
+    /// Return the number of submitted requests that [`Client::complete`] has
+    /// not returned yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len() + self.completed.len()
     }
 
     /// Send a read command to the NBD server.
     pub fn read(&mut self, offset: u64, len: u32) -> Result<Vec<u8>> {
-        let req = Request::new(Cmd::READ, offset, len);
-        req.put(&[], &mut self.conn)?;
-        let mut buf = vec![0; len as usize];
-        self.get_reply_data(&req, &mut buf)?;
-        Ok(buf)
+        Ok(self.run(Command::Read { offset, len })?.buf)
     }
 
     /// Send a write command to the NBD server.
+    ///
+    /// Writes larger than 4GiB require extended headers.
     pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        let req = Request::new(Cmd::WRITE, offset, data.len() as u32);
-        req.put(data, &mut self.conn)?;
-        self.get_ack(&req)?;
+        self.run(Command::Write { offset, data })?;
+        Ok(())
+    }
+
+    /// Write zeroes to `len` bytes starting at `offset`.
+    ///
+    /// Unless `no_hole` is set, the server may deallocate the range. If `fast`
+    /// is set, the server fails the request rather than zero the range slowly
+    /// (so that the caller can fall back to something else).
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, no_hole: bool, fast: bool) -> Result<()> {
+        self.run(Command::WriteZeroes {
+            offset,
+            len,
+            no_hole,
+            fast,
+        })?;
+        Ok(())
+    }
+
+    /// Discard `len` bytes starting at `offset`, which may read as anything
+    /// afterward.
+    pub fn trim(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.run(Command::Trim { offset, len })?;
+        Ok(())
+    }
+
+    /// Ask the server to prefetch `len` bytes starting at `offset`, so that
+    /// reading them later is faster.
+    pub fn cache(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.run(Command::Cache { offset, len })?;
+        Ok(())
+    }
+
+    /// Ask the server to change the size of the export to `size` bytes.
+    ///
+    /// Sizes of 4GiB or more require extended headers. Note that [`Client::size`]
+    /// still returns the size from the handshake.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        self.request(Request::new(Cmd::RESIZE, 0, size), &[])?;
         Ok(())
     }
 
     /// Send a flush command to the NBD server.
     pub fn flush(&mut self) -> Result<()> {
-        let req = Request::new(Cmd::FLUSH, 0, 0);
-        req.put(&[], &mut self.conn)?;
-        self.get_ack(&req)?;
+        self.run(Command::Flush)?;
         Ok(())
     }
 
+    /// Query the status of `len` bytes starting at `offset` in each of the
+    /// selected metadata contexts, returning the extents by context name.
+    ///
+    /// The extents start at `offset`, but the server may describe less than
+    /// the whole range.
+    pub fn block_status(&mut self, offset: u64, len: u64) -> Result<BTreeMap<String, Vec<Extent>>> {
+        ensure!(
+            !self.negotiated.meta_contexts.is_empty(),
+            "no metadata contexts were negotiated"
+        );
+        let done = self.request(Request::new(Cmd::BLOCK_STATUS, offset, len), &[])?;
+        self.negotiated.block_status(done)
+    }
+
     /// Disconnect from server cleanly and consume this client.
     pub fn disconnect(mut self) -> Result<()> {
-        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut self.conn)?;
+        let extended = self.negotiated.extended_headers;
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut self.conn, extended)?;
         Ok(())
     }
 }
@@ -147,8 +1064,576 @@ impl Client<TcpStream> {
     }
 }
 
+impl Client<UnixStream> {
+    /// Connect to a server listening on a Unix socket, run handshake, and
+    /// return a `Client` prepared for the transmission phase.
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Self::new(stream)
+    }
+}
+
+/// A connection to a server over TCP or a Unix socket, for clients that
+/// support both (see [`Client::connect_uri`]).
+#[derive(Debug)]
+pub enum Socket {
+    /// A TCP connection.
+    Tcp(TcpStream),
+    /// A Unix socket connection.
+    Unix(UnixStream),
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl IntoRawFd for Socket {
+    fn into_raw_fd(self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.into_raw_fd(),
+            Socket::Unix(stream) => stream.into_raw_fd(),
+        }
+    }
+}
+
+impl Client<Socket> {
+    /// Connect to the export named by an NBD URI (for example,
+    /// `nbd://host/export` or `nbd+unix:///export?socket=/run/nbd.sock`; see
+    /// [`crate::uri`]), and run the handshake.
+    pub fn connect_uri(uri: &str) -> Result<Self> {
+        let uri: NbdUri = uri.parse()?;
+        let stream = match &uri.transport {
+            Transport::Tcp { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port))?;
+                stream.set_nodelay(true)?;
+                Socket::Tcp(stream)
+            }
+            Transport::Unix(path) => Socket::Unix(UnixStream::connect(path)?),
+        };
+        Self::with_options(stream, &uri.client_options()?)
+    }
+}
+
+/// # Panics
+///
+/// Panics if the connection uses TLS, since the TLS session cannot be handed
+/// off with the socket.
 impl<IO: Read + Write + IntoRawFd> IntoRawFd for Client<IO> {
     fn into_raw_fd(self) -> RawFd {
-        self.conn.into_raw_fd()
+        match self.conn {
+            Conn::Plain(stream) => stream.into_raw_fd(),
+            Conn::Tls(_) => panic!("cannot take the socket of a TLS connection"),
+        }
+    }
+}
+
+/// A stream the async client can use, such as a socket or a TLS session.
+#[cfg(feature = "tokio")]
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+/// A request waiting for its reply in an [`AsyncClient`].
+#[cfg(feature = "tokio")]
+struct Waiting {
+    pending: InFlight,
+    done: oneshot::Sender<Result<Done>>,
+}
+
+/// The requests an [`AsyncClient`] is waiting for, shared with the task that
+/// reads replies.
+#[cfg(feature = "tokio")]
+#[derive(Default)]
+struct AsyncState {
+    in_flight: BTreeMap<u64, Waiting>,
+    // why the connection can no longer be used
+    closed: Option<String>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncState {
+    /// Stop using the connection, failing the requests in flight.
+    fn close(&mut self, reason: String) {
+        self.closed.get_or_insert(reason);
+        // dropping the senders wakes up the waiting requests
+        self.in_flight.clear();
+    }
+
+    fn closed_error(&self) -> color_eyre::Report {
+        eyre!(
+            "connection closed: {}",
+            self.closed.as_deref().unwrap_or("unknown reason")
+        )
+    }
+}
+
+/// A message for the task that writes to the connection.
+#[cfg(feature = "tokio")]
+enum Outgoing {
+    Request(Vec<u8>),
+    // the last request, after which the connection is shut down
+    Disconnect(Vec<u8>, oneshot::Sender<Result<()>>),
+}
+
+#[cfg(feature = "tokio")]
+struct AsyncInner {
+    export: Export,
+    negotiated: Negotiated,
+    tls: bool,
+    state: Arc<Mutex<AsyncState>>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    next_handle: AtomicU64,
+    tasks: [JoinHandle<()>; 2],
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for AsyncInner {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// AsyncClient is a [`Client`] for tokio.
+///
+/// Clones share the connection, so many tasks can send requests
+/// concurrently: each request gets its own handle and replies are matched to
+/// requests as they arrive, in any order. Dropping the last clone closes the
+/// connection.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct AsyncClient(Arc<AsyncInner>);
+
+#[cfg(feature = "tokio")]
+impl fmt::Debug for AsyncClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncClient")
+            .field("export", &self.0.export)
+            .field("negotiated", &self.0.negotiated)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncClient {
+    /// Establish a handshake with stream and return an `AsyncClient` ready for
+    /// use.
+    ///
+    /// Must be called from a tokio runtime, which runs the tasks that read
+    /// and write the connection.
+    pub async fn new<S>(stream: S) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::with_options(stream, &ClientOptions::default()).await
+    }
+
+    /// Establish a handshake with stream, requesting the features in `opts`.
+    pub async fn with_options<S>(stream: S, opts: &ClientOptions) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut stream = Tokio(stream);
+        let (fixed, no_zeroes) = match initial_handshake(&mut stream, opts.legacy).await? {
+            Greeting::Newstyle { fixed, no_zeroes } => (fixed, no_zeroes),
+            Greeting::Oldstyle(export) => {
+                ensure!(opts.tls.is_none(), "server does not support TLS");
+                return Ok(Self::ready(
+                    Box::new(stream.0),
+                    false,
+                    export,
+                    Negotiated::default(),
+                ));
+            }
+        };
+        if !fixed {
+            // options other than NBD_OPT_EXPORT_NAME cannot be refused without
+            // closing the connection
+            ensure!(opts.tls.is_none(), "server does not support TLS");
+            let export = select_export(&mut stream, &opts.export_name, no_zeroes).await?;
+            return Ok(Self::ready(
+                Box::new(stream.0),
+                false,
+                export,
+                Negotiated::default(),
+            ));
+        }
+        let conn: Box<dyn AsyncStream> = match &opts.tls {
+            Some(tls) => {
+                request_tls(&mut stream).await?;
+                Box::new(tls.connect_async(stream.0).await?)
+            }
+            None => Box::new(stream.0),
+        };
+        let mut conn = Tokio(conn);
+        let (export, negotiated) = handshake_haggle(&mut conn, opts, no_zeroes).await?;
+        Ok(Self::ready(conn.0, opts.tls.is_some(), export, negotiated))
+    }
+
+    /// Create a client for the transmission phase, starting the tasks that
+    /// read and write the connection.
+    fn ready(
+        conn: Box<dyn AsyncStream>,
+        tls: bool,
+        export: Export,
+        negotiated: Negotiated,
+    ) -> Self {
+        let (reader, writer) = tokio::io::split(conn);
+        let state = Arc::new(Mutex::new(AsyncState::default()));
+        let (outgoing, requests) = mpsc::unbounded_channel();
+        let tasks = [
+            tokio::spawn(Self::read_replies(Tokio(reader), state.clone())),
+            tokio::spawn(Self::write_requests(writer, requests, state.clone())),
+        ];
+        Self(Arc::new(AsyncInner {
+            export,
+            negotiated,
+            tls,
+            state,
+            outgoing,
+            next_handle: AtomicU64::new(0),
+            tasks,
+        }))
+    }
+
+    /// Read replies and complete the requests they are for, until the
+    /// connection fails.
+    async fn read_replies<R>(mut conn: Tokio<R>, state: Arc<Mutex<AsyncState>>)
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        let err = loop {
+            if let Err(err) = Self::read_reply(&mut conn, &state).await {
+                break err;
+            }
+        };
+        state.lock().unwrap().close(format!("{err:#}"));
+    }
+
+    async fn read_reply<R>(conn: &mut Tokio<R>, state: &Mutex<AsyncState>) -> Result<()>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        let max_payload = |header: &ReplyHeader| {
+            let state = state.lock().unwrap();
+            match state.in_flight.get(&header.handle()) {
+                Some(waiting) => Ok(waiting.pending.max_payload()),
+                None => bail!(ProtocolError::new(format!(
+                    "reply for unknown handle {}",
+                    header.handle()
+                ))),
+            }
+        };
+        let reply = conn.get_reply(max_payload).await?;
+        let handle = reply.handle();
+        let (finished, has_data) = {
+            let mut state = state.lock().unwrap();
+            let Some(waiting) = state.in_flight.get_mut(&handle) else {
+                bail!(ProtocolError::new(format!(
+                    "reply for unknown handle {handle}"
+                )));
+            };
+            match reply {
+                Reply::Simple { err, .. } => {
+                    if err != ErrorType::OK {
+                        waiting.pending.fail(err);
+                    }
+                    (state.in_flight.remove(&handle), err == ErrorType::OK)
+                }
+                Reply::Chunk(chunk) => match waiting.pending.add_chunk(chunk)? {
+                    true => (state.in_flight.remove(&handle), false),
+                    false => (None, false),
+                },
+            }
+        };
+        if let Some(mut waiting) = finished {
+            if has_data {
+                conn.read_exact(&mut waiting.pending.buf).await?;
+            }
+            // the caller may have stopped waiting
+            let _ = waiting.done.send(waiting.pending.finish());
+        }
+        Ok(())
+    }
+
+    /// Write requests in the order they are sent, flushing whenever there are
+    /// no more to write.
+    async fn write_requests<W>(
+        mut conn: W,
+        mut requests: mpsc::UnboundedReceiver<Outgoing>,
+        state: Arc<Mutex<AsyncState>>,
+    ) where
+        W: AsyncWrite + Send + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        while let Some(outgoing) = requests.recv().await {
+            let result = match outgoing {
+                Outgoing::Request(buf) => {
+                    let mut result = conn.write_all(&buf).await;
+                    if result.is_ok() && requests.is_empty() {
+                        result = conn.flush().await;
+                    }
+                    result
+                }
+                Outgoing::Disconnect(buf, written) => {
+                    let mut result = conn.write_all(&buf).await;
+                    if result.is_ok() {
+                        result = conn.shutdown().await;
+                    }
+                    let _ = written.send(result.wrap_err("sending disconnect"));
+                    return;
+                }
+            };
+            if let Err(err) = result {
+                state
+                    .lock()
+                    .unwrap()
+                    .close(format!("sending request: {err}"));
+                return;
+            }
+        }
+    }
+
+    /// Send a request and wait for its reply.
+    async fn request(&self, mut req: Request, data: &[u8]) -> Result<Done> {
+        self.0.export.check_block_sizes(&req)?;
+        let handle = self.0.next_handle.fetch_add(1, Ordering::Relaxed);
+        req.handle = handle;
+        let mut buf = vec![];
+        req.put(data, &mut buf, self.0.negotiated.extended_headers)?;
+        let (done, reply) = oneshot::channel();
+        {
+            // register the request first, since the reply can arrive as soon
+            // as it is written
+            let mut state = self.0.state.lock().unwrap();
+            if state.closed.is_some() {
+                return Err(state.closed_error());
+            }
+            let pending = InFlight::new(req);
+            state.in_flight.insert(handle, Waiting { pending, done });
+        }
+        if self.0.outgoing.send(Outgoing::Request(buf)).is_err() {
+            return Err(self.0.state.lock().unwrap().closed_error());
+        }
+        match reply.await {
+            Ok(result) => result,
+            Err(_) => Err(self.0.state.lock().unwrap().closed_error()),
+        }
+    }
+
+    /// Send a command and wait for its reply.
+    async fn run(&self, cmd: Command<'_>) -> Result<Done> {
+        let (req, data) = cmd.into_request();
+        self.request(req, data).await
+    }
+
+    /// Return the size of this export, as reported by the server during the
+    /// handshake.
+    pub fn size(&self) -> u64 {
+        self.0.export.size
+    }
+
+    /// Return the transmission flags the server sent for the export, which
+    /// say which commands it supports.
+    pub fn transmit_flags(&self) -> TransmitFlags {
+        self.0.export.flags
+    }
+
+    /// Return the block size constraints the server sent for the export (see
+    /// [`Client::block_sizes`]).
+    pub fn block_sizes(&self) -> BlockSizes {
+        self.0.export.block_sizes.unwrap_or_default()
+    }
+
+    /// Return the server's canonical name for the export, if it reported one.
+    pub fn export_name(&self) -> Option<&str> {
+        self.0.export.name.as_deref()
+    }
+
+    /// Return the server's human-readable description of the export, if it
+    /// has one.
+    pub fn description(&self) -> Option<&str> {
+        self.0.export.description.as_deref()
+    }
+
+    /// Return whether the server agreed to send structured replies.
+    pub fn structured_replies(&self) -> bool {
+        self.0.negotiated.structured_replies
+    }
+
+    /// Return whether the connection uses TLS.
+    pub fn tls(&self) -> bool {
+        self.0.tls
+    }
+
+    /// Return whether the server agreed to use extended headers.
+    pub fn extended_headers(&self) -> bool {
+        self.0.negotiated.extended_headers
+    }
+
+    /// Return the names of the metadata contexts the server selected.
+    pub fn meta_contexts(&self) -> Vec<&str> {
+        self.0
+            .negotiated
+            .meta_contexts
+            .values()
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    /// Read `len` bytes starting at `offset`.
+    pub async fn read(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+        Ok(self.run(Command::Read { offset, len }).await?.buf)
+    }
+
+    /// Write `data` starting at `offset`.
+    ///
+    /// Writes larger than 4GiB require extended headers.
+    pub async fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.run(Command::Write { offset, data }).await?;
+        Ok(())
+    }
+
+    /// Write zeroes to `len` bytes starting at `offset` (see
+    /// [`Client::write_zeroes`]).
+    pub async fn write_zeroes(
+        &self,
+        offset: u64,
+        len: u64,
+        no_hole: bool,
+        fast: bool,
+    ) -> Result<()> {
+        self.run(Command::WriteZeroes {
+            offset,
+            len,
+            no_hole,
+            fast,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Discard `len` bytes starting at `offset`, which may read as anything
+    /// afterward.
+    pub async fn trim(&self, offset: u64, len: u64) -> Result<()> {
+        self.run(Command::Trim { offset, len }).await?;
+        Ok(())
+    }
+
+    /// Ask the server to prefetch `len` bytes starting at `offset`.
+    pub async fn cache(&self, offset: u64, len: u64) -> Result<()> {
+        self.run(Command::Cache { offset, len }).await?;
+        Ok(())
+    }
+
+    /// Flush all completed writes to stable storage.
+    ///
+    /// Writes that are still in flight from other tasks are not necessarily
+    /// covered.
+    pub async fn flush(&self) -> Result<()> {
+        self.run(Command::Flush).await?;
+        Ok(())
+    }
+
+    /// Query the status of `len` bytes starting at `offset` in each of the
+    /// selected metadata contexts (see [`Client::block_status`]).
+    pub async fn block_status(
+        &self,
+        offset: u64,
+        len: u64,
+    ) -> Result<BTreeMap<String, Vec<Extent>>> {
+        ensure!(
+            !self.0.negotiated.meta_contexts.is_empty(),
+            "no metadata contexts were negotiated"
+        );
+        let done = self
+            .request(Request::new(Cmd::BLOCK_STATUS, offset, len), &[])
+            .await?;
+        self.0.negotiated.block_status(done)
+    }
+
+    /// Disconnect from the server cleanly.
+    ///
+    /// Requests already in flight still get their replies, but new requests
+    /// fail, including from clones of this client.
+    pub async fn disconnect(&self) -> Result<()> {
+        let mut buf = vec![];
+        let extended = self.0.negotiated.extended_headers;
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut buf, extended)?;
+        let (written, done) = oneshot::channel();
+        {
+            let mut state = self.0.state.lock().unwrap();
+            if state.closed.is_some() {
+                return Err(state.closed_error());
+            }
+            state.closed = Some("disconnected".to_string());
+        }
+        if self
+            .0
+            .outgoing
+            .send(Outgoing::Disconnect(buf, written))
+            .is_err()
+        {
+            return Err(self.0.state.lock().unwrap().closed_error());
+        }
+        match done.await {
+            Ok(result) => result,
+            Err(_) => Err(self.0.state.lock().unwrap().closed_error()),
+        }
+    }
+
+    /// Connect to a server, run the handshake, and return an `AsyncClient`
+    /// prepared for the transmission phase.
+    pub async fn connect(host: &str, port: u16) -> Result<Self> {
+        let stream = tokio::net::TcpStream::connect((host, port)).await?;
+        Self::new(stream).await
+    }
+
+    /// Connect to a server listening on a Unix socket and run the handshake.
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Self::new(stream).await
+    }
+
+    /// Connect to the export named by an NBD URI (see [`Client::connect_uri`])
+    /// and run the handshake.
+    pub async fn connect_uri(uri: &str) -> Result<Self> {
+        let uri: NbdUri = uri.parse()?;
+        let opts = uri.client_options()?;
+        match &uri.transport {
+            Transport::Tcp { host, port } => {
+                let stream = tokio::net::TcpStream::connect((host.as_str(), *port)).await?;
+                stream.set_nodelay(true)?;
+                Self::with_options(stream, &opts).await
+            }
+            Transport::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                Self::with_options(stream, &opts).await
+            }
+        }
     }
 }
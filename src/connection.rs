@@ -0,0 +1,192 @@
+//! Reading and writing protocol messages over blocking or async streams.
+//!
+//! The handshakes and the transmission phase are written once, as async code
+//! over [`Connection`] (or [`ReadMessages`], for just reading). Blocking
+//! streams use [`Blocking`], whose futures are ready the first time they are
+//! polled, and run the code with [`block_on`]; tokio streams use `Tokio`.
+
+use std::future::Future;
+use std::io::{self, prelude::*};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+
+use crate::proto::*;
+
+/// The reading side of a connection.
+pub(crate) trait ReadMessages {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Read the next option.
+    async fn get_opt(&mut self) -> Result<Opt> {
+        let mut header = [0u8; Opt::HEADER_LEN];
+        self.read_exact(&mut header).await?;
+        let (typ, option_len) = Opt::get_header(&mut &header[..])?;
+        let mut data = vec![0u8; option_len as usize];
+        self.read_exact(&mut data)
+            .await
+            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
+        Ok(Opt { typ, data })
+    }
+
+    /// Read the next reply to an option.
+    async fn get_opt_reply(&mut self) -> Result<OptReply> {
+        let mut header = [0u8; OptReply::HEADER_LEN];
+        self.read_exact(&mut header).await?;
+        let (opt, reply_type, len) = OptReply::get_header(&mut &header[..])?;
+        let mut data = vec![0u8; len as usize];
+        self.read_exact(&mut data)
+            .await
+            .wrap_err_with(|| format!("reading {reply_type:?} reply to {opt:?}"))?;
+        Ok(OptReply {
+            opt,
+            reply_type,
+            data,
+        })
+    }
+
+    /// Read the next request, storing the data for a write request in buf
+    /// (see [`Request::get`]).
+    async fn get_request(&mut self, buf: &mut [u8], extended: bool) -> Result<Request> {
+        let mut header = [0u8; 32];
+        let header = &mut header[..Request::header_len(extended)];
+        self.read_exact(header).await?;
+        let mut req = Request::get_header(&mut &header[..], extended)?;
+        if req.typ == Cmd::WRITE {
+            req.data_len = (req.len.min(buf.len() as u64)) as usize;
+            self.read_exact(&mut buf[..req.data_len])
+                .await
+                .wrap_err_with(|| format!("parsing write request of length {}", req.data_len))?;
+            // keep the stream in sync for the next request
+            let mut extra = req.len - req.data_len as u64;
+            let mut discard = vec![0u8; extra.min(64 * 1024) as usize];
+            while extra > 0 {
+                let n = extra.min(discard.len() as u64) as usize;
+                self.read_exact(&mut discard[..n]).await?;
+                extra -= n as u64;
+            }
+        }
+        Ok(req)
+    }
+
+    /// Read the next reply to a request (see [`Reply::get`]).
+    async fn get_reply(
+        &mut self,
+        max_payload: impl FnOnce(&ReplyHeader) -> Result<usize>,
+    ) -> Result<Reply> {
+        let mut header = [0u8; ReplyHeader::MAX_LEN];
+        self.read_exact(&mut header[..4]).await?;
+        let magic = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let header_len = ReplyHeader::header_len(magic)?;
+        self.read_exact(&mut header[4..header_len]).await?;
+        let header = ReplyHeader::get(&mut &header[..header_len])?;
+        header.check_payload_len(max_payload(&header)?)?;
+        let mut payload = vec![0u8; header.payload_len()];
+        self.read_exact(&mut payload)
+            .await
+            .wrap_err_with(|| format!("reading payload of {header:?}"))?;
+        Reply::from_header(header, &payload)
+    }
+}
+
+/// A connection to a client or server.
+pub(crate) trait Connection: ReadMessages {
+    /// Write all of buf and flush it.
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Send an option.
+    async fn put_opt(&mut self, opt: Opt) -> Result<()> {
+        let mut out = vec![];
+        opt.put(&mut out)?;
+        self.write_all(&out).await?;
+        Ok(())
+    }
+}
+
+/// A blocking stream, whose operations are always ready.
+pub(crate) struct Blocking<IO>(pub IO);
+
+impl<IO: Read> ReadMessages for Blocking<IO> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buf)
+    }
+
+    // read directly from the stream, rather than through a header buffer
+
+    async fn get_opt(&mut self) -> Result<Opt> {
+        Opt::get(&mut self.0)
+    }
+
+    async fn get_opt_reply(&mut self) -> Result<OptReply> {
+        OptReply::get(&mut self.0)
+    }
+
+    async fn get_request(&mut self, buf: &mut [u8], extended: bool) -> Result<Request> {
+        Request::get(&mut self.0, buf, extended)
+    }
+
+    async fn get_reply(
+        &mut self,
+        max_payload: impl FnOnce(&ReplyHeader) -> Result<usize>,
+    ) -> Result<Reply> {
+        Reply::get(&mut self.0, max_payload)
+    }
+}
+
+impl<IO: Read + Write> Connection for Blocking<IO> {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)?;
+        self.0.flush()
+    }
+}
+
+/// A tokio stream.
+#[cfg(feature = "tokio")]
+pub(crate) struct Tokio<IO>(pub IO);
+
+#[cfg(feature = "tokio")]
+impl<IO: tokio::io::AsyncRead + Unpin + Send> ReadMessages for Tokio<IO> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        tokio::io::AsyncReadExt::read_exact(&mut self.0, buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<IO> Connection for Tokio<IO>
+where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+{
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::write_all(&mut self.0, buf).await?;
+        tokio::io::AsyncWriteExt::flush(&mut self.0).await
+    }
+}
+
+/// Run a future to completion on the current thread.
+///
+/// With blocking connections and Blocks, the future is ready the first time
+/// it is polled; otherwise the thread sleeps until it is woken.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -26,7 +26,7 @@
 
 #![deny(missing_docs)]
 
-use color_eyre::eyre::WrapErr;
+use color_eyre::eyre::{ensure, WrapErr};
 use color_eyre::Result;
 
 use std::io::{self, prelude::*};
@@ -37,6 +37,10 @@ use std::{
 
 use crate::{client::Client, proto::TransmitFlags};
 
+// Smallest block size the kernel is configured with, which the device size
+// is a multiple of.
+const BLOCK_SIZE: u64 = 4096;
+
 /// Wrappers for NBD ioctls.
 ///
 /// See <https://github.com/NetworkBlockDevice/nbd/blob/master/nbd.h>.
@@ -55,6 +59,9 @@ mod ioctl {
     ioctl_none_bad!(disconnect, request_code_none!(NBD_IOCTL, 8));
     ioctl_write_int_bad!(set_timeout, request_code_none!(NBD_IOCTL, 9));
     ioctl_write_int_bad!(set_flags, request_code_none!(NBD_IOCTL, 10));
+
+    // BLKSSZGET, for any block device
+    nix::ioctl_read_bad!(get_logical_block_size, 0x1268, libc::c_int);
 }
 
 /// Set socket for an NBD device opened at `f`. Should be connected to an NBD server.
@@ -78,6 +85,14 @@ fn set_blksize(f: &File, blksize: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// Get the block size of the NBD device opened at `f`.
+fn get_blksize(f: &File) -> io::Result<u64> {
+    let fd = f.as_raw_fd();
+    let mut blksize = 0;
+    unsafe { ioctl::get_logical_block_size(fd, &mut blksize)? };
+    Ok(blksize as u64)
+}
+
 /// Set size in bytes for an NBD device opened at `f`.
 #[allow(dead_code)]
 fn set_size(f: &File, bytes: u64) -> io::Result<()> {
@@ -120,8 +135,11 @@ fn set_flags(f: &File, flags: TransmitFlags) -> io::Result<()> {
 ///
 /// Client must use an underlying connection which is based on a raw file
 /// descriptor, since this is what is sent to the kernel. In practice a
-/// `TcpStream` is likely to be this connection, but it could also be a socket
-/// to an in-process server.
+/// `TcpStream` or `UnixStream` (see [`Client::connect_unix`]) is likely to be
+/// this connection, but it could also be a socket to an in-process server.
+/// The kernel only speaks the basic protocol, so the client must not have
+/// negotiated TLS, structured replies, or extended headers (which
+/// [`ClientOptions::default`](crate::client::ClientOptions) avoids).
 ///
 /// The protocol here is probably best reverse-engineered by running an NBD
 /// server (`cargo run` will work), then running `strace` over `nbd-client` (run
@@ -167,11 +185,29 @@ fn set_flags(f: &File, flags: TransmitFlags) -> io::Result<()> {
 /// NBD_SET_SOCK, 4)`, which is the really important part. Then the process
 /// calls `clone` to keep running in the background.
 pub fn set_client<IO: Read + Write + IntoRawFd>(nbd: &File, client: Client<IO>) -> Result<()> {
+    ensure!(!client.tls(), "the kernel does not support TLS connections");
+    ensure!(
+        !client.structured_replies(),
+        "the kernel does not support structured replies"
+    );
+    ensure!(
+        !client.extended_headers(),
+        "the kernel does not support extended headers"
+    );
     let size = client.size();
-    set_blksize(nbd, 4096)?;
-    set_size_blocks(nbd, size / 4096)?;
+    // the kernel only sends requests of whole blocks, which must meet the
+    // server's minimum
+    let blksize = BLOCK_SIZE.max(client.block_sizes().minimum as u64);
+    set_blksize(nbd, blksize)?;
+    set_size_blocks(nbd, size / blksize)?;
 
-    let flags = TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH;
+    // pass on the flags the kernel understands, if the server sent them
+    let kernel_flags = TransmitFlags::READ_ONLY
+        | TransmitFlags::SEND_FLUSH
+        | TransmitFlags::SEND_FUA
+        | TransmitFlags::SEND_TRIM
+        | TransmitFlags::CAN_MULTI_CONN;
+    let flags = TransmitFlags::HAS_FLAGS | (client.transmit_flags() & kernel_flags);
     set_flags(nbd, flags)?;
 
     clear_sock(nbd)?;
@@ -181,6 +217,19 @@ pub fn set_client<IO: Read + Write + IntoRawFd>(nbd: &File, client: Client<IO>)
     Ok(())
 }
 
+/// Change the size of an NBD device that is already set up, for example after
+/// the export was resized.
+///
+/// The size is rounded down to a multiple of the block size set by
+/// [`set_client`] (4096 bytes, or the server's minimum block size if that is
+/// larger). The kernel notifies users of the device (such as file systems)
+/// about the new size.
+pub fn resize(nbd: &File, size: u64) -> Result<()> {
+    let blksize = get_blksize(nbd).wrap_err("could not get nbd block size")?;
+    set_size_blocks(nbd, size / blksize).wrap_err("could not set nbd size")?;
+    Ok(())
+}
+
 /// Wait for an initialized NBD device to be closed.
 pub fn wait(nbd: &File) -> Result<()> {
     do_it(nbd).wrap_err("waiting for NBD with DO_IT ioctl")?;
@@ -1,16 +1,33 @@
 pub mod client;
+mod connection;
 pub mod kernel;
 pub mod proto;
 pub mod server;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod uri;
+#[cfg(feature = "io-uring")]
+pub mod uring;
 
 #[cfg(test)]
 mod tests {
     use color_eyre::Result;
     use readwrite::ReadWrite;
     use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
     use std::thread::{self, JoinHandle};
 
-    use crate::server::MemBlocks;
+    use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+
+    use crate::client::{ClientOptions, Command, ExportEntry, NegotiationError};
+    use crate::proto::{
+        BlockSizes, ChunkPayload, Cmd, DirtyBitmapFlags, ErrorType, Extent, Opt, OptReply, OptType,
+        Reply, ReplyFlags, ReplyType, Request, TransmitFlags, BASE_ALLOCATION, QEMU_DIRTY_BITMAP,
+    };
+    use crate::server::{Blocks, ExportOptions, Handshake, MemBlocks, DEFAULT_EXPORT};
+    use crate::testing::TempFile;
+    use crate::tls::{self, ClientTls, ServerTls, TlsPolicy};
     use crate::{client::Client, server::Server};
 
     struct ServerClient<IO: Read + Write> {
@@ -27,6 +44,20 @@ mod tests {
     }
 
     fn start_server_client(data: Vec<u8>) -> Result<ServerClient<impl Read + Write>> {
+        start_server_client_with(data, &ClientOptions::default())
+    }
+
+    fn start_server_client_with(
+        data: Vec<u8>,
+        opts: &ClientOptions,
+    ) -> Result<ServerClient<impl Read + Write>> {
+        start_client(Server::new(MemBlocks::new(data)), opts)
+    }
+
+    fn start_client(
+        server: Server,
+        opts: &ClientOptions,
+    ) -> Result<ServerClient<impl Read + Write>> {
         let _ = env_logger::builder().is_test(true).try_init();
         let (r1, w1) = pipe::pipe();
         let (r2, w2) = pipe::pipe();
@@ -34,12 +65,34 @@ mod tests {
         let s2 = ReadWrite::new(r2, w1);
 
         let s_handle = thread::spawn(move || -> Result<()> {
-            let server = Server::new(MemBlocks::new(data));
             server.handle_client(s1)?;
             Ok(())
         });
 
-        let client = Client::new(s2)?;
+        let client = Client::with_options(s2, opts)?;
+
+        Ok(ServerClient {
+            server: s_handle,
+            client,
+        })
+    }
+
+    /// Like [`start_client`], but over a socket rather than a pipe: TLS needs
+    /// buffering, since both sides can write at the same time (for example,
+    /// the server sends session tickets while the client sends options).
+    fn start_socket_client(
+        server: Server,
+        opts: &ClientOptions,
+    ) -> Result<ServerClient<UnixStream>> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (s1, s2) = UnixStream::pair()?;
+
+        let s_handle = thread::spawn(move || -> Result<()> {
+            server.handle_client(s1)?;
+            Ok(())
+        });
+
+        let client = Client::with_options(s2, opts)?;
 
         Ok(ServerClient {
             server: s_handle,
@@ -97,4 +150,1402 @@ mod tests {
         sc.shutdown()?;
         Ok(())
     }
+
+    #[test]
+    fn structured_read_holes() -> Result<()> {
+        let mut data = vec![0u8; 4096 * 4];
+        data[4096..4096 + 10].fill(2);
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_with(data.clone(), &opts)?;
+        let client = &mut sc.client;
+        assert!(client.structured_replies());
+
+        let buf = client.read(100, 4096 * 3)?;
+        assert_eq!(buf, &data[100..100 + 4096 * 3]);
+        client.write(4096 * 3 + 5, &[3u8; 4])?;
+        let buf = client.read(0, 4096 * 4)?;
+        assert_eq!(
+            &buf[4096 * 3..4096 * 3 + 10],
+            [0, 0, 0, 0, 0, 3, 3, 3, 3, 0]
+        );
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn structured_read_error_offset() -> Result<()> {
+        let data = vec![1u8; 4096 * 2];
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_with(data, &opts)?;
+        let client = &mut sc.client;
+
+        let err = client.read(4096, 4096 * 2).unwrap_err();
+        assert!(
+            err.to_string().contains("offset 8192"),
+            "unexpected error {err}"
+        );
+        // the connection is still usable after a failed read
+        assert_eq!(client.read(0, 3)?, [1u8; 3]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn extended_headers_read_write() -> Result<()> {
+        let data = vec![1u8; 1024 * 10];
+        let opts = ClientOptions {
+            extended_headers: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_with(data, &opts)?;
+        let client = &mut sc.client;
+        assert!(client.extended_headers());
+        assert!(client.structured_replies());
+
+        client.write(4, &[9u8; 7])?;
+        client.flush()?;
+        assert_eq!(client.read(2, 4)?, [1, 1, 9, 9]);
+        assert!(client.read(1024 * 10, 1).is_err());
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn block_status_allocation() -> Result<()> {
+        let mut data = vec![0u8; 4096 * 4];
+        data[4096 * 2] = 1;
+        let opts = ClientOptions {
+            structured_replies: true,
+            meta_contexts: vec![BASE_ALLOCATION.to_string()],
+            ..Default::default()
+        };
+        let mut sc = start_server_client_with(data, &opts)?;
+        let client = &mut sc.client;
+        assert_eq!(client.meta_contexts(), [BASE_ALLOCATION]);
+
+        let status = client.block_status(0, 4096 * 4)?;
+        assert_eq!(
+            status[BASE_ALLOCATION],
+            [
+                Extent::zero(4096 * 2),
+                Extent::data(4096),
+                Extent::zero(4096)
+            ]
+        );
+        assert!(client.block_status(4096, 4096 * 4).is_err());
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn block_status_extended() -> Result<()> {
+        let data = vec![1u8; 4096 * 2];
+        let opts = ClientOptions {
+            extended_headers: true,
+            meta_contexts: vec![BASE_ALLOCATION.to_string(), "base:other".to_string()],
+            ..Default::default()
+        };
+        let mut sc = start_server_client_with(data, &opts)?;
+        let client = &mut sc.client;
+        assert_eq!(client.meta_contexts(), [BASE_ALLOCATION]);
+
+        let status = client.block_status(10, 4096)?;
+        assert_eq!(status[BASE_ALLOCATION], [Extent::data(4096)]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_bitmap_checkpoint() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024 * 1024]));
+        server.create_checkpoint(DEFAULT_EXPORT, "nightly")?;
+        assert!(server.create_checkpoint(DEFAULT_EXPORT, "nightly").is_err());
+        let bitmap = format!("{QEMU_DIRTY_BITMAP}nightly");
+        let opts = ClientOptions {
+            structured_replies: true,
+            meta_contexts: vec![bitmap.clone()],
+            ..Default::default()
+        };
+        let mut sc = start_client(server.clone(), &opts)?;
+        let client = &mut sc.client;
+        assert_eq!(client.meta_contexts(), [bitmap.as_str()]);
+
+        client.write(64 * 1024 * 2 + 10, &[1u8; 100])?;
+        let status = client.block_status(0, 1024 * 1024)?;
+        let extents = &status[&bitmap];
+        assert_eq!(extents.len(), 3);
+        assert_eq!(extents[0].length, 64 * 1024 * 2);
+        assert!(extents[0].dirty_bitmap().is_empty());
+        assert_eq!(extents[1].length, 64 * 1024);
+        assert_eq!(extents[1].dirty_bitmap(), DirtyBitmapFlags::DIRTY);
+
+        server.clear_checkpoint(DEFAULT_EXPORT, "nightly")?;
+        let status = client.block_status(0, 1024 * 1024)?;
+        assert_eq!(
+            status[&bitmap],
+            [Extent {
+                length: 1024 * 1024,
+                flags: 0
+            }]
+        );
+
+        server.remove_checkpoint(DEFAULT_EXPORT, "nightly")?;
+        assert!(client.block_status(0, 1024).is_err());
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn tls_psk_read_write() -> Result<()> {
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let tls = ServerTls::psk(keys)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Required);
+        let opts = ClientOptions {
+            structured_replies: true,
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            ..Default::default()
+        };
+        let mut sc = start_socket_client(server, &opts)?;
+        let client = &mut sc.client;
+        assert!(client.tls());
+        assert!(client.structured_replies());
+
+        client.write(100, &[1, 2, 3])?;
+        assert_eq!(client.read(99, 5)?, [0, 1, 2, 3, 0]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn tls_psk_wrong_key() -> Result<()> {
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let tls = ServerTls::psk(keys)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Required);
+        let opts = ClientOptions {
+            tls: Some(ClientTls::psk("alice", &[0xa5; 32])),
+            ..Default::default()
+        };
+        assert!(start_socket_client(server, &opts).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tls_x509_read_write() -> Result<()> {
+        let (cert, key) = tls::tests::self_signed_cert("x509-read-write")?;
+        let tls = ServerTls::x509(&cert, &key, None)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Optional);
+        let opts = ClientOptions {
+            tls: Some(ClientTls::x509(Some(&cert)).hostname("localhost")),
+            ..Default::default()
+        };
+        let mut sc = start_socket_client(server, &opts)?;
+        let client = &mut sc.client;
+        assert!(client.tls());
+
+        client.write(0, &[7u8; 10])?;
+        assert_eq!(client.read(0, 11)?, [7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn tls_optional_allows_plain() -> Result<()> {
+        let (cert, key) = tls::tests::self_signed_cert("optional-plain")?;
+        let tls = ServerTls::x509(&cert, &key, None)?;
+        let server = Server::with_tls(MemBlocks::new(vec![1u8; 4096]), tls, TlsPolicy::Optional);
+        let mut sc = start_socket_client(server, &ClientOptions::default())?;
+        assert!(!sc.client.tls());
+        assert_eq!(sc.client.read(0, 2)?, [1, 1]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn tls_required_refuses_plain() -> Result<()> {
+        let (cert, key) = tls::tests::self_signed_cert("required-plain")?;
+        let tls = ServerTls::x509(&cert, &key, None)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Required);
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        assert!(start_socket_client(server, &opts).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tls_unsupported() -> Result<()> {
+        let opts = ClientOptions {
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            ..Default::default()
+        };
+        assert!(start_server_client_with(vec![0u8; 4096], &opts).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_exports() -> Result<()> {
+        let server = Server::empty(None);
+        server.add_export(
+            "mem",
+            MemBlocks::new(vec![1u8; 4096]),
+            ExportOptions::default(),
+        )?;
+        let file = TempFile::new("exports")?;
+        file.set_len(8192)?;
+        server.add_export("file", file.try_clone()?, ExportOptions::default())?;
+        assert!(server
+            .add_export("mem", MemBlocks::new(vec![]), ExportOptions::default())
+            .is_err());
+        assert_eq!(server.exports(), ["file", "mem"]);
+
+        let opts = ClientOptions {
+            export_name: "file".to_string(),
+            ..Default::default()
+        };
+        let mut sc = start_client(server.clone(), &opts)?;
+        assert_eq!(sc.client.size(), 8192);
+        sc.client.write(0, &[2u8; 10])?;
+        assert_eq!(sc.client.read(0, 11)?, [2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 0]);
+        sc.shutdown()?;
+
+        let opts = ClientOptions {
+            export_name: "mem".to_string(),
+            ..Default::default()
+        };
+        let mut sc = start_client(server.clone(), &opts)?;
+        assert_eq!(sc.client.size(), 4096);
+        assert_eq!(sc.client.read(0, 2)?, [1, 1]);
+        sc.shutdown()?;
+
+        let opts = ClientOptions {
+            export_name: "missing".to_string(),
+            ..Default::default()
+        };
+        assert!(start_client(server.clone(), &opts).is_err());
+
+        server.remove_export("mem")?;
+        assert_eq!(server.exports(), ["file"]);
+        Ok(())
+    }
+
+    #[test]
+    fn default_export_name() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        for name in ["", DEFAULT_EXPORT] {
+            let opts = ClientOptions {
+                export_name: name.to_string(),
+                ..Default::default()
+            };
+            let sc = start_client(server.clone(), &opts)?;
+            assert_eq!(sc.client.size(), 4096);
+            assert_eq!(sc.client.export_name(), Some(DEFAULT_EXPORT));
+            assert_eq!(sc.client.description(), None);
+            sc.shutdown()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn export_description() -> Result<()> {
+        let server = Server::empty(None);
+        server.add_export(
+            "disk0",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                description: Some("boot disk for vm1".to_string()),
+                ..Default::default()
+            },
+        )?;
+        let opts = ClientOptions {
+            export_name: "disk0".to_string(),
+            ..Default::default()
+        };
+        let sc = start_client(server, &opts)?;
+        assert_eq!(sc.client.export_name(), Some("disk0"));
+        assert_eq!(sc.client.description(), Some("boot disk for vm1"));
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn selective_tls() -> Result<()> {
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let server = Server::empty(Some(ServerTls::psk(keys)?));
+        server.add_export(
+            "public",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions::default(),
+        )?;
+        server.add_export(
+            "secret",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                tls: TlsPolicy::Required,
+                ..Default::default()
+            },
+        )?;
+
+        let opts = ClientOptions {
+            export_name: "public".to_string(),
+            ..Default::default()
+        };
+        start_socket_client(server.clone(), &opts)?.shutdown()?;
+
+        let opts = ClientOptions {
+            export_name: "secret".to_string(),
+            ..Default::default()
+        };
+        let err = start_socket_client(server.clone(), &opts).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<NegotiationError>(),
+            Some(NegotiationError::TlsRequired(_))
+        ));
+
+        let opts = ClientOptions {
+            export_name: "secret".to_string(),
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            ..Default::default()
+        };
+        let sc = start_socket_client(server, &opts)?;
+        assert!(sc.client.tls());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn block_size_constraints() -> Result<()> {
+        let server = Server::empty(None);
+        let block_sizes = BlockSizes {
+            minimum: 512,
+            preferred: 4096,
+            maximum: 64 * 1024,
+        };
+        server.add_export(
+            DEFAULT_EXPORT,
+            MemBlocks::new(vec![0u8; 1024 * 1024]),
+            ExportOptions {
+                block_sizes,
+                ..Default::default()
+            },
+        )?;
+        let mut sc = start_client(server.clone(), &ClientOptions::default())?;
+        let client = &mut sc.client;
+        assert_eq!(client.block_sizes(), block_sizes);
+
+        client.write(512, &[1u8; 512])?;
+        assert_eq!(client.read(512, 512)?, [1u8; 512]);
+        // the client refuses these requests without sending them
+        let err = client.read(1, 512).unwrap_err();
+        assert!(err.to_string().contains("minimum block size"), "{err}");
+        assert!(client.read(0, 100).is_err());
+        let err = client.write(0, &vec![2u8; 128 * 1024]).unwrap_err();
+        assert!(err.to_string().contains("maximum block size"), "{err}");
+        assert_eq!(client.read(0, 1024)?[512..], [1u8; 512]);
+
+        sc.shutdown()?;
+
+        let invalid = BlockSizes {
+            minimum: 3,
+            ..block_sizes
+        };
+        assert!(server
+            .add_export(
+                "invalid",
+                MemBlocks::new(vec![]),
+                ExportOptions {
+                    block_sizes: invalid,
+                    ..Default::default()
+                },
+            )
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_export() -> Result<()> {
+        let server = Server::empty(None);
+        server.add_export(
+            DEFAULT_EXPORT,
+            MemBlocks::new(vec![3u8; 4096]),
+            ExportOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )?;
+        let mut sc = start_client(server, &ClientOptions::default())?;
+        let client = &mut sc.client;
+        assert!(client.write(0, &[1u8; 10]).is_err());
+        assert_eq!(client.read(0, 4)?, [3, 3, 3, 3]);
+        client.flush()?;
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn write_zeroes() -> Result<()> {
+        let mut sc = start_server_client(vec![1u8; 4096 * 4])?;
+        let client = &mut sc.client;
+        client.write_zeroes(10, 4096, false, false)?;
+        client.write_zeroes(4096 * 2, 100, true, true)?;
+        let data = client.read(0, 4096 * 4)?;
+        assert_eq!(data[..10], [1u8; 10]);
+        assert_eq!(data[10..4096 + 10], [0u8; 4096]);
+        assert_eq!(data[4096 + 10..4096 * 2], [1u8; 4096 - 10]);
+        assert_eq!(data[4096 * 2..4096 * 2 + 100], [0u8; 100]);
+        assert!(client.write_zeroes(4096 * 4 - 1, 2, false, false).is_err());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// MemBlocks behind only the required methods of Blocks (and, as async
+    /// Blocks, yielding before every operation like a backend waiting for
+    /// I/O, and waiting on a tokio timer for slow reads).
+    #[derive(Clone)]
+    struct TestBlocks {
+        mem: MemBlocks,
+        // how long reads at offset 0 take
+        slow_read: std::time::Duration,
+        // how long writes take
+        slow_write: std::time::Duration,
+        // completed writes and started flushes, in order
+        log: std::sync::Arc<std::sync::Mutex<Vec<Event>>>,
+    }
+
+    /// An operation recorded by [`TestBlocks`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Wrote(u64),
+        Flush,
+    }
+
+    impl TestBlocks {
+        fn new(data: Vec<u8>) -> Self {
+            Self {
+                mem: MemBlocks::new(data),
+                slow_read: std::time::Duration::ZERO,
+                slow_write: std::time::Duration::ZERO,
+                log: Default::default(),
+            }
+        }
+
+        /// Make reads at offset 0 take `delay`.
+        fn slow_read(self, delay: std::time::Duration) -> Self {
+            Self {
+                slow_read: delay,
+                ..self
+            }
+        }
+
+        /// Make every write take `delay`.
+        fn slow_write(self, delay: std::time::Duration) -> Self {
+            Self {
+                slow_write: delay,
+                ..self
+            }
+        }
+
+        fn log(&self) -> Vec<Event> {
+            self.log.lock().unwrap().clone()
+        }
+    }
+
+    impl Blocks for TestBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            if off == 0 {
+                thread::sleep(self.slow_read);
+            }
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            thread::sleep(self.slow_write);
+            self.mem.write_at(buf, off)?;
+            self.log.lock().unwrap().push(Event::Wrote(off));
+            Ok(())
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            self.log.lock().unwrap().push(Event::Flush);
+            self.mem.flush()
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[crate::server::async_trait]
+    impl crate::server::AsyncBlocks for TestBlocks {
+        async fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            tokio::task::yield_now().await;
+            if off == 0 {
+                tokio::time::sleep(self.slow_read).await;
+            }
+            self.mem.read_at(buf, off)
+        }
+
+        async fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            tokio::task::yield_now().await;
+            Blocks::write_at(self, buf, off)
+        }
+
+        async fn size(&self) -> std::io::Result<u64> {
+            tokio::task::yield_now().await;
+            Blocks::size(self)
+        }
+
+        async fn flush(&self) -> std::io::Result<()> {
+            tokio::task::yield_now().await;
+            Blocks::flush(self)
+        }
+    }
+
+    #[test]
+    fn write_zeroes_slow() -> Result<()> {
+        let server = Server::new(TestBlocks::new(vec![1u8; 4096]));
+        let mut sc = start_client(server, &ClientOptions::default())?;
+        let client = &mut sc.client;
+        // zeroing by writing is not fast
+        assert!(client.write_zeroes(0, 100, false, true).is_err());
+        assert_eq!(client.read(0, 2)?, [1, 1]);
+        client.write_zeroes(0, 100, false, false)?;
+        assert_eq!(client.read(99, 2)?, [0, 1]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn trim() -> Result<()> {
+        let mut sc = start_server_client(vec![1u8; 4096 * 4])?;
+        let client = &mut sc.client;
+        client.trim(4096, 4096 * 2)?;
+        assert_eq!(client.read(4096 - 1, 2)?, [1, 0]);
+        assert!(client.trim(4096 * 3, 4096 * 2).is_err());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn cache() -> Result<()> {
+        let file = TempFile::new("cache")?;
+        file.set_len(4096 * 4)?;
+        let mut sc = start_client(Server::new(file.try_clone()?), &ClientOptions::default())?;
+        let client = &mut sc.client;
+        client.cache(0, 4096 * 4)?;
+        assert!(client.cache(4096, 4096 * 4).is_err());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn resize() -> Result<()> {
+        let server = Server::new(MemBlocks::with_max_size(vec![1u8; 4096], 4096 * 3));
+        server.create_checkpoint(DEFAULT_EXPORT, "before")?;
+        let opts = ClientOptions {
+            extended_headers: true,
+            meta_contexts: vec![format!("{QEMU_DIRTY_BITMAP}before")],
+            ..Default::default()
+        };
+        let mut sc = start_client(server.clone(), &opts)?;
+        let client = &mut sc.client;
+        client.resize(4096 * 3)?;
+        assert_eq!(client.read(4095, 2)?, [1, 0]);
+        client.write(4096 * 2, &[2u8; 10])?;
+        let status = client.block_status(0, 4096 * 3)?;
+        assert_eq!(
+            status[&format!("{QEMU_DIRTY_BITMAP}before")][0].dirty_bitmap(),
+            DirtyBitmapFlags::DIRTY
+        );
+
+        // growing beyond the maximum is refused
+        assert!(client.resize(1 << 40).is_err());
+        assert!(client.resize(4096 * 4).is_err());
+        client.resize(100)?;
+        assert!(client.read(100, 1).is_err());
+        sc.shutdown()?;
+
+        // new clients see the new size
+        let sc = start_client(server, &ClientOptions::default())?;
+        assert_eq!(sc.client.size(), 100);
+        sc.shutdown()?;
+
+        // resizing is only offered if the Blocks support it
+        let sc = start_client(
+            Server::new(TestBlocks::new(vec![0u8; 4096])),
+            &ClientOptions::default(),
+        )?;
+        assert!(!sc
+            .client
+            .transmit_flags()
+            .contains(TransmitFlags::SEND_RESIZE));
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn multi_conn() -> Result<()> {
+        let file = TempFile::new("multi-conn")?;
+        file.set_len(4096)?;
+        let server = Server::new(file.try_clone()?);
+        let mut sc1 = start_client(server.clone(), &ClientOptions::default())?;
+        let mut sc2 = start_client(server, &ClientOptions::default())?;
+        assert!(sc1
+            .client
+            .transmit_flags()
+            .contains(TransmitFlags::CAN_MULTI_CONN));
+
+        sc1.client.write(0, &[1, 2, 3])?;
+        sc2.client.flush()?;
+        assert_eq!(sc2.client.read(0, 3)?, [1, 2, 3]);
+
+        sc1.shutdown()?;
+        sc2.shutdown()?;
+
+        // backends have to opt in
+        let sc = start_client(
+            Server::new(TestBlocks::new(vec![0u8; 4096])),
+            &ClientOptions::default(),
+        )?;
+        assert!(!sc
+            .client
+            .transmit_flags()
+            .contains(TransmitFlags::CAN_MULTI_CONN));
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// Start a newstyle handshake by hand, as a client that may not support
+    /// fixed newstyle negotiation.
+    fn start_raw_client(
+        server: Server,
+        client_flags: u32,
+    ) -> Result<(JoinHandle<Result<()>>, UnixStream)> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (s1, mut s2) = UnixStream::pair()?;
+        let s_handle = thread::spawn(move || server.handle_client(s1));
+        s2.read_u64::<BE>()?; // NBDMAGIC
+        s2.read_u64::<BE>()?; // IHAVEOPT
+        s2.read_u16::<BE>()?;
+        s2.write_u32::<BE>(client_flags)?;
+        Ok((s_handle, s2))
+    }
+
+    #[test]
+    fn oldstyle_handshake() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        server.set_handshake(Handshake::Oldstyle);
+        let opts = ClientOptions {
+            legacy: true,
+            ..Default::default()
+        };
+        let mut sc = start_socket_client(server.clone(), &opts)?;
+        assert_eq!(sc.client.size(), 4096);
+        assert!(sc
+            .client
+            .transmit_flags()
+            .contains(TransmitFlags::SEND_FLUSH));
+        sc.client.write(0, &[1, 2, 3])?;
+        assert_eq!(sc.client.read(0, 3)?, [1, 2, 3]);
+        sc.shutdown()?;
+
+        // clients have to opt in
+        assert!(start_socket_client(server, &ClientOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn non_fixed_newstyle() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        // refused by default
+        let (handle, stream) = start_raw_client(server.clone(), 0)?;
+        assert!(handle.join().unwrap().is_err());
+        drop(stream);
+
+        server.set_handshake(Handshake::Newstyle);
+        let (handle, mut stream) = start_raw_client(server.clone(), 0)?;
+        Opt {
+            typ: OptType::EXPORT_NAME,
+            data: vec![],
+        }
+        .put(&mut stream)?;
+        assert_eq!(stream.read_u64::<BE>()?, 4096);
+        stream.read_u16::<BE>()?;
+        // without NO_ZEROES, the export information is padded
+        let mut zeroes = [1u8; 124];
+        stream.read_exact(&mut zeroes)?;
+        assert_eq!(zeroes, [0u8; 124]);
+        drop(stream);
+        handle.join().unwrap()?;
+
+        // only NBD_OPT_EXPORT_NAME, NBD_OPT_LIST, and NBD_OPT_ABORT are allowed
+        let (handle, mut stream) = start_raw_client(server.clone(), 0)?;
+        Opt {
+            typ: OptType::STRUCTURED_REPLY,
+            data: vec![],
+        }
+        .put(&mut stream)?;
+        assert!(handle.join().unwrap().is_err());
+
+        // fixed newstyle clients are unaffected
+        let sc = start_client(server, &ClientOptions::default())?;
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn structured_read_rejected() -> Result<()> {
+        let server = Server::empty(None);
+        let block_sizes = BlockSizes {
+            minimum: 512,
+            ..Default::default()
+        };
+        server.add_export(
+            DEFAULT_EXPORT,
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                block_sizes,
+                ..Default::default()
+            },
+        )?;
+        let (handle, mut stream) = start_raw_client(server, 0b11)?;
+        Opt {
+            typ: OptType::STRUCTURED_REPLY,
+            data: vec![],
+        }
+        .put(&mut stream)?;
+        assert_eq!(OptReply::get(&mut stream)?.reply_type, ReplyType::ACK);
+        Opt {
+            typ: OptType::EXPORT_NAME,
+            data: vec![],
+        }
+        .put(&mut stream)?;
+        stream.read_u64::<BE>()?;
+        stream.read_u16::<BE>()?;
+
+        // a misaligned read is refused with an error chunk, not a simple reply
+        let req = Request::new(Cmd::READ, 1, 512);
+        req.put(&[], &mut stream, false)?;
+        match Reply::get(&mut stream, |_| Ok(4096))? {
+            Reply::Chunk(chunk) => {
+                assert!(chunk.flags.contains(ReplyFlags::DONE));
+                assert!(
+                    matches!(chunk.payload, ChunkPayload::Error { err, .. } if err == ErrorType::EINVAL),
+                    "unexpected chunk {chunk:?}"
+                );
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        drop(stream);
+        handle.join().unwrap()?;
+        Ok(())
+    }
+
+    /// Connect to a server that might still be starting up, retrying
+    /// `connect` until it succeeds.
+    fn connect_retry<T>(connect: impl Fn() -> Result<T>) -> Result<T> {
+        for _ in 0..100 {
+            if let Ok(conn) = connect() {
+                return Ok(conn);
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        connect()
+    }
+
+    /// Start a server on a Unix socket in the temporary directory, returning
+    /// the socket's path.
+    fn start_unix_server(server: Server, name: &str) -> std::path::PathBuf {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let path = std::env::temp_dir().join(format!("nbd-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let path = path.clone();
+            thread::spawn(move || server.start_unix(path));
+        }
+        path
+    }
+
+    #[test]
+    fn unix_socket() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        let path = start_unix_server(server, "unix");
+        let mut client = connect_retry(|| Client::connect_unix(&path))?;
+        assert_eq!(client.size(), 4096);
+        client.write(0, &[1, 2, 3])?;
+        assert_eq!(client.read(0, 3)?, [1, 2, 3]);
+        client.disconnect()?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_requests() -> Result<()> {
+        let blocks = TestBlocks::new(vec![0u8; 64 * 1024])
+            .slow_read(std::time::Duration::from_millis(200))
+            .slow_write(std::time::Duration::from_millis(20));
+        let server = Server::new(blocks.clone());
+        server.set_workers(4);
+        let path = start_unix_server(server, "workers");
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let stream = connect_retry(|| Ok(UnixStream::connect(&path)?))?;
+        let mut client = Client::with_options(stream, &opts)?;
+        client.write(4096, &[1; 512])?;
+        // the fast read is not stuck behind the slow one
+        let slow = client.submit(Command::Read {
+            offset: 0,
+            len: 512,
+        })?;
+        let fast = client.submit(Command::Read {
+            offset: 4096,
+            len: 512,
+        })?;
+        let completion = client.complete()?;
+        assert_eq!(completion.handle, fast);
+        assert_eq!(completion.result?, [1; 512]);
+        let completion = client.complete()?;
+        assert_eq!(completion.handle, slow);
+        assert_eq!(completion.result?, [0; 512]);
+
+        // a flush after writes from several workers waits for all of them
+        for i in 0..16 {
+            client.submit(Command::Write {
+                offset: 8192 + i * 512,
+                data: &[i as u8 + 1; 512],
+            })?;
+        }
+        client.submit(Command::Flush)?;
+        while client.in_flight() > 0 {
+            client.complete()?.result?;
+        }
+        let log = blocks.log();
+        let flush = log.iter().position(|&event| event == Event::Flush).unwrap();
+        let written = log[..flush]
+            .iter()
+            .filter(|&&event| matches!(event, Event::Wrote(off) if off >= 8192))
+            .count();
+        assert_eq!(written, 16, "flush overtook writes: {log:?}");
+        for i in 0..16 {
+            assert_eq!(client.read(8192 + i * 512, 512)?, [i as u8 + 1; 512]);
+        }
+        assert!(client.read(64 * 1024, 1).is_err());
+        client.disconnect()?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_requests_reset() -> Result<()> {
+        let blocks =
+            TestBlocks::new(vec![0u8; 64 * 1024]).slow_write(std::time::Duration::from_millis(100));
+        let server = Server::new(blocks.clone());
+        server.set_workers(2);
+        let path = start_unix_server(server.clone(), "workers-reset");
+        let stream = connect_retry(|| Ok(UnixStream::connect(&path)?))?;
+        let mut client = Client::new(stream.try_clone()?)?;
+        // more writes than workers, so the flush waits for a queued one
+        for i in 0..3 {
+            client.submit(Command::Write {
+                offset: i * 512,
+                data: &[1; 512],
+            })?;
+        }
+        client.submit(Command::Flush)?;
+        stream.shutdown(std::net::Shutdown::Both)?;
+        drop(client);
+        // the connection ends, releasing its export
+        server.remove_export(DEFAULT_EXPORT)?;
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while std::sync::Arc::strong_count(&blocks.log) > 1 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "connection did not end"
+            );
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn listen_on_addresses() -> Result<()> {
+        let free_port = || -> Result<u16> {
+            Ok(std::net::TcpListener::bind("127.0.0.1:0")?
+                .local_addr()?
+                .port())
+        };
+        let (port1, port2) = (free_port()?, free_port()?);
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        let addrs = [
+            format!("127.0.0.1:{port1}").parse()?,
+            format!("[::1]:{port1}").parse()?,
+            format!("[::]:{port2}").parse()?,
+        ];
+        thread::spawn(move || server.start_on(&addrs));
+
+        // the IPv6 wildcard address also accepts IPv4 connections
+        for addr in [
+            format!("127.0.0.1:{port1}"),
+            format!("[::1]:{port1}"),
+            format!("127.0.0.1:{port2}"),
+            format!("[::1]:{port2}"),
+        ] {
+            let addr: std::net::SocketAddr = addr.parse()?;
+            let client = connect_retry(|| Client::new(std::net::TcpStream::connect(addr)?))?;
+            assert_eq!(client.size(), 4096);
+            client.disconnect()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn connect_uri() -> Result<()> {
+        let psk_file = std::env::temp_dir().join(format!("nbd-uri-{}.psk", std::process::id()));
+        std::fs::write(&psk_file, "alice:5a5a5a5a\n")?;
+        let server = Server::empty(Some(ServerTls::psk_file(&psk_file)?));
+        server.add_export(
+            "disk 1",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions::default(),
+        )?;
+        let socket = start_unix_server(server, "uri");
+        let uri = format!("nbd+unix:///disk%201?socket={}", socket.display());
+        let mut client = connect_retry(|| Client::connect_uri(&uri))?;
+        assert_eq!(client.size(), 4096);
+        assert!(!client.tls());
+        client.write(0, &[1, 2, 3])?;
+        client.disconnect()?;
+
+        let uri = format!(
+            "nbds+unix://alice@/disk%201?socket={}&tls-psk-file={}",
+            socket.display(),
+            psk_file.display()
+        );
+        let mut client = Client::connect_uri(&uri)?;
+        assert!(client.tls());
+        assert_eq!(client.read(0, 3)?, [1, 2, 3]);
+        client.disconnect()?;
+
+        std::fs::remove_file(&socket)?;
+        std::fs::remove_file(&psk_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn go_negotiation() -> Result<()> {
+        let server = Server::empty(None);
+        server.add_export(
+            "disk",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )?;
+        let opts = ClientOptions {
+            export_name: "disk".to_string(),
+            ..Default::default()
+        };
+        let sc = start_client(server.clone(), &opts)?;
+        assert_eq!(sc.client.size(), 4096);
+        assert_eq!(sc.client.export_name(), Some("disk"));
+        assert!(sc
+            .client
+            .transmit_flags()
+            .contains(TransmitFlags::READ_ONLY));
+        assert_eq!(sc.client.block_sizes(), BlockSizes::default());
+        sc.shutdown()?;
+
+        let opts = ClientOptions {
+            export_name: "missing".to_string(),
+            ..Default::default()
+        };
+        let err = start_client(server, &opts).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<NegotiationError>(),
+            Some(NegotiationError::UnknownExport(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn list_exports() -> Result<()> {
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let server = Server::empty(Some(ServerTls::psk(keys)?));
+        server.add_export(
+            "disk1",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                description: Some("first disk".to_string()),
+                ..Default::default()
+            },
+        )?;
+        server.add_export(
+            "disk2",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions::default(),
+        )?;
+        server.add_export(
+            "secret",
+            MemBlocks::new(vec![0u8; 4096]),
+            ExportOptions {
+                tls: TlsPolicy::Required,
+                ..Default::default()
+            },
+        )?;
+
+        let list = |opts: &ClientOptions| -> Result<Vec<String>> {
+            let (s1, s2) = UnixStream::pair()?;
+            let server = server.clone();
+            let handle = thread::spawn(move || server.handle_client(s1));
+            let exports = Client::list_exports(s2, opts)?;
+            handle.join().unwrap()?;
+            Ok(exports.into_iter().map(|export| export.name).collect())
+        };
+        assert_eq!(list(&ClientOptions::default())?, ["disk1", "disk2"]);
+        let opts = ClientOptions {
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            ..Default::default()
+        };
+        assert_eq!(list(&opts)?, ["disk1", "disk2", "secret"]);
+
+        let (s1, s2) = UnixStream::pair()?;
+        thread::spawn(move || server.handle_client(s1));
+        let exports = Client::list_exports(s2, &ClientOptions::default())?;
+        assert_eq!(
+            exports[0],
+            ExportEntry {
+                name: "disk1".to_string(),
+                description: Some("first disk".to_string()),
+            }
+        );
+        assert_eq!(exports[1].description, None);
+        Ok(())
+    }
+
+    #[test]
+    fn pipelined_requests() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 64 * 1024]));
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let mut sc = start_socket_client(server, &opts)?;
+        let client = &mut sc.client;
+
+        let data: Vec<Vec<u8>> = (0..16u8).map(|i| vec![i; 4096]).collect();
+        let mut writes = std::collections::BTreeSet::new();
+        for (i, data) in data.iter().enumerate() {
+            let offset = i as u64 * 4096;
+            writes.insert(client.submit(Command::Write { offset, data })?);
+        }
+        assert_eq!(client.in_flight(), 16);
+        while client.in_flight() > 0 {
+            let completion = client.complete()?;
+            assert!(writes.remove(&completion.handle));
+            completion.result?;
+        }
+        assert!(writes.is_empty());
+
+        let mut reads = std::collections::BTreeMap::new();
+        for i in 0..16 {
+            let offset = i as u64 * 4096;
+            reads.insert(client.submit(Command::Read { offset, len: 4096 })?, i);
+        }
+        let bad_read = client.submit(Command::Read {
+            offset: 64 * 1024,
+            len: 4096,
+        })?;
+        // waiting for another request sets aside the submitted ones
+        client.flush()?;
+        assert_eq!(client.in_flight(), 17);
+        while client.in_flight() > 0 {
+            let completion = client.complete()?;
+            if completion.handle == bad_read {
+                assert!(completion.result.is_err());
+                continue;
+            }
+            let i = reads.remove(&completion.handle).unwrap();
+            assert_eq!(completion.result?, data[i]);
+        }
+        assert!(client.complete().is_err());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// Like [`start_socket_client`], but the server handles the client with
+    /// [`Server::handle_client_async`] on a tokio runtime.
+    #[cfg(feature = "tokio")]
+    fn start_async_client(
+        server: Server,
+        opts: &ClientOptions,
+    ) -> Result<ServerClient<UnixStream>> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (s1, s2) = UnixStream::pair()?;
+        s1.set_nonblocking(true)?;
+
+        let s_handle = thread::spawn(move || -> Result<()> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let s1 = tokio::net::UnixStream::from_std(s1)?;
+                server.handle_client_async(s1).await
+            })
+        });
+
+        let client = Client::with_options(s2, opts)?;
+
+        Ok(ServerClient {
+            server: s_handle,
+            client,
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let _guard = runtime.enter();
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        server.add_async_export(
+            "async",
+            TestBlocks::new(vec![0u8; 64 * 1024]),
+            ExportOptions::default(),
+        )?;
+        for name in [DEFAULT_EXPORT, "async"] {
+            let opts = ClientOptions {
+                export_name: name.to_string(),
+                structured_replies: true,
+                ..Default::default()
+            };
+            let mut sc = start_async_client(server.clone(), &opts)?;
+            let client = &mut sc.client;
+            client.write(100, &[1, 2, 3])?;
+            assert_eq!(client.read(99, 5)?, [0, 1, 2, 3, 0]);
+            client.write_zeroes(101, 1, false, false)?;
+            assert_eq!(client.read(99, 5)?, [0, 1, 0, 3, 0]);
+            client.flush()?;
+            assert!(client.read(client.size(), 1).is_err());
+            sc.shutdown()?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_slow_blocks() -> Result<()> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let blocks = TestBlocks::new(vec![0u8; 4096]).slow_read(std::time::Duration::from_secs(1));
+        let server = Server::new(blocks);
+        // both connections are served by one runtime thread
+        let (s1, c1) = UnixStream::pair()?;
+        let (s2, c2) = UnixStream::pair()?;
+        s1.set_nonblocking(true)?;
+        s2.set_nonblocking(true)?;
+        let s_handle = thread::spawn(move || -> Result<()> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()?;
+            runtime.block_on(async {
+                let s1 = tokio::net::UnixStream::from_std(s1)?;
+                let first = tokio::spawn({
+                    let server = server.clone();
+                    async move { server.handle_client_async(s1).await }
+                });
+                server
+                    .handle_client_async(tokio::net::UnixStream::from_std(s2)?)
+                    .await?;
+                first.await?
+            })
+        });
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let slow = thread::spawn(move || -> Result<()> {
+            let mut client = Client::new(c1)?;
+            client.read(0, 512)?;
+            done_tx.send(()).unwrap();
+            client.disconnect()
+        });
+        thread::sleep(std::time::Duration::from_millis(100));
+        // the slow read does not hold up another connection
+        let mut client = Client::new(c2)?;
+        assert_eq!(client.read(512, 512)?, [0; 512]);
+        assert!(done_rx.try_recv().is_err());
+        client.disconnect()?;
+        slow.join().unwrap()?;
+        s_handle.join().unwrap()
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_tls() -> Result<()> {
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let tls = ServerTls::psk(keys)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Required);
+        let opts = ClientOptions {
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            ..Default::default()
+        };
+        let mut sc = start_async_client(server, &opts)?;
+        let client = &mut sc.client;
+        assert!(client.tls());
+        client.write(100, &[1, 2, 3])?;
+        assert_eq!(client.read(99, 5)?, [0, 1, 2, 3, 0]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_export_blocking_server() -> Result<()> {
+        let server = Server::empty(None);
+        let blocks =
+            TestBlocks::new(vec![0u8; 4096]).slow_read(std::time::Duration::from_millis(10));
+        // the export needs a runtime for its timer
+        assert!(server
+            .add_async_export(DEFAULT_EXPORT, blocks.clone(), ExportOptions::default())
+            .is_err());
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            server.add_async_export(DEFAULT_EXPORT, blocks, ExportOptions::default())
+        })?;
+        let mut sc = start_socket_client(server, &ClientOptions::default())?;
+        let client = &mut sc.client;
+        assert_eq!(client.size(), 4096);
+        client.write(1, &[1, 2, 3])?;
+        assert_eq!(client.read(0, 5)?, [0, 1, 2, 3, 0]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn start_async() -> Result<()> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        let addr: std::net::SocketAddr = format!("127.0.0.1:{port}").parse()?;
+        tokio::spawn(server.start_async(vec![addr]));
+        // connect from several threads at once
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                tokio::task::spawn_blocking(move || -> Result<()> {
+                    let mut client =
+                        connect_retry(|| Client::new(std::net::TcpStream::connect(addr)?))?;
+                    client.write(i * 10, &[i as u8 + 1; 10])?;
+                    assert_eq!(client.read(i * 10, 10)?, [i as u8 + 1; 10]);
+                    client.disconnect()
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await??;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_client() -> Result<()> {
+        use crate::client::AsyncClient;
+
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (s1, s2) = UnixStream::pair()?;
+        s2.set_nonblocking(true)?;
+        let server = Server::new(MemBlocks::new(vec![0u8; 64 * 1024]));
+        let s_handle = thread::spawn(move || server.handle_client(s1));
+
+        let opts = ClientOptions {
+            structured_replies: true,
+            meta_contexts: vec![BASE_ALLOCATION.to_string()],
+            ..Default::default()
+        };
+        let client =
+            AsyncClient::with_options(tokio::net::UnixStream::from_std(s2)?, &opts).await?;
+        assert_eq!(client.size(), 64 * 1024);
+        assert_eq!(client.meta_contexts(), [BASE_ALLOCATION]);
+        // many tasks share the connection, each with its own part of the export
+        let tasks: Vec<_> = (0..16u8)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let off = i as u64 * 4096;
+                    for j in 0..8 {
+                        client.write(off + j * 512, &[i + 1; 512]).await?;
+                    }
+                    assert_eq!(client.read(off, 4096).await?, vec![i + 1; 4096]);
+                    client.trim(off, 512).await?;
+                    client.write_zeroes(off + 512, 512, true, false).await?;
+                    let data = client.read(off + 512, 1024).await?;
+                    assert_eq!(&data[..512], [0; 512]);
+                    assert_eq!(&data[512..], [i + 1; 512]);
+                    client.flush().await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await??;
+        }
+        // errors only fail their own request
+        let (bad, good) = tokio::join!(client.read(client.size(), 1), client.read(5120, 1));
+        assert!(bad.is_err());
+        assert_eq!(good?, [2]);
+        let status = client.block_status(0, 4096).await?;
+        assert!(!status[BASE_ALLOCATION].is_empty());
+
+        client.disconnect().await?;
+        assert!(client.read(0, 1).await.is_err());
+        tokio::task::spawn_blocking(move || s_handle.join().unwrap()).await??;
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_client_tls() -> Result<()> {
+        use crate::client::AsyncClient;
+
+        let keys = [("alice".to_string(), vec![0x5a; 32])].into();
+        let tls = ServerTls::psk(keys)?;
+        let server = Server::with_tls(MemBlocks::new(vec![0u8; 4096]), tls, TlsPolicy::Required);
+        let (s1, s2) = tokio::net::UnixStream::pair()?;
+        let s_handle = tokio::spawn(async move { server.handle_client_async(s1).await });
+
+        let opts = ClientOptions {
+            tls: Some(ClientTls::psk("alice", &[0x5a; 32])),
+            extended_headers: true,
+            ..Default::default()
+        };
+        let client = AsyncClient::with_options(s2, &opts).await?;
+        assert!(client.tls());
+        assert!(client.extended_headers());
+        let (w1, w2) = tokio::join!(client.write(100, &[1, 2, 3]), client.write(200, &[4]));
+        w1?;
+        w2?;
+        let (r1, r2) = tokio::join!(client.read(99, 5), client.read(200, 1));
+        assert_eq!(r1?, [0, 1, 2, 3, 0]);
+        assert_eq!(r2?, [4]);
+        client.disconnect().await?;
+        s_handle.await??;
+        Ok(())
+    }
 }
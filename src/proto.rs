@@ -7,7 +7,7 @@
 #![allow(non_camel_case_types)]
 use color_eyre::eyre::{bail, ensure, WrapErr};
 use color_eyre::Result;
-use log::{error, warn};
+use log::warn;
 use rand::Rng;
 use std::error::Error;
 use std::fmt;
@@ -22,11 +22,15 @@ pub const DEFAULT_PORT: u16 = 10809;
 
 pub(crate) const MAGIC: u64 = 0x4e42444d41474943; // b"NBDMAGIC"
 pub(crate) const IHAVEOPT: u64 = 0x49484156454F5054; // b"IHAVEOPT"
+pub(crate) const CLISERV_MAGIC: u64 = 0x00420281861253; // oldstyle negotiation
 pub(crate) const REPLY_MAGIC: u64 = 0x3e889045565a9;
 
 // transmission constants
 pub(crate) const REQUEST_MAGIC: u32 = 0x25609513;
+pub(crate) const EXTENDED_REQUEST_MAGIC: u32 = 0x21e41c71;
 pub(crate) const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+pub(crate) const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+pub(crate) const EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
 
 #[derive(Debug, Clone)]
 pub(crate) struct ProtocolError(String);
@@ -58,20 +62,36 @@ bitflags! {
     const C_FIXED_NEWSTYLE = 0b01;
     const C_NO_ZEROES = 0b10;
   }
+}
 
-  #[derive(Copy, Clone, Debug)]
-  pub(crate) struct TransmitFlags: u16 {
+bitflags! {
+  /// Transmission flags, which describe an export and the commands the
+  /// server supports for it.
+  #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+  pub struct TransmitFlags: u16 {
+    /// Always set by servers.
     const HAS_FLAGS = 1 << 0;
+    /// The export is read-only.
     const READ_ONLY = 1 << 1;
+    /// The server supports `NBD_CMD_FLUSH`.
     const SEND_FLUSH = 1 << 2;
+    /// The server supports forced unit access on writes.
     const SEND_FUA = 1 << 3;
+    /// The export has the characteristics of a rotational medium.
     const ROTATIONAL = 1 << 4;
+    /// The server supports `NBD_CMD_TRIM`.
     const SEND_TRIM = 1 << 5;
+    /// The server supports `NBD_CMD_WRITE_ZEROES`.
     const SEND_WRITE_ZEROES = 1 << 6;
+    /// The server supports the don't-fragment flag on reads.
     const SEND_DF = 1 << 7;
+    /// Multiple connections to the export see each other's flushed writes.
     const CAN_MULTI_CONN = 1 << 8;
+    /// The server supports `NBD_CMD_RESIZE`.
     const SEND_RESIZE = 1 << 9;
+    /// The server supports `NBD_CMD_CACHE`.
     const SEND_CACHE = 1 << 10;
+    /// The server supports the fast-zero flag on `NBD_CMD_WRITE_ZEROES`.
     const SEND_FAST_ZERO = 1 << 11;
   }
 }
@@ -86,6 +106,10 @@ pub(crate) enum OptType {
     STARTTLS = 5,
     INFO = 6,
     GO = 7,
+    STRUCTURED_REPLY = 8,
+    LIST_META_CONTEXT = 9,
+    SET_META_CONTEXT = 10,
+    EXTENDED_HEADERS = 11,
 }
 
 #[derive(IntoPrimitive, TryFromPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
@@ -103,6 +127,7 @@ pub(crate) enum ReplyType {
     ACK = 1,
     SERVER = 2,
     INFO = 3,
+    META_CONTEXT = 4,
     ERR_UNSUP = (1 << 31) + 1,
     ERR_POLICY = (1 << 31) + 2,
     ERR_INVALID = (1 << 31) + 3,
@@ -111,14 +136,15 @@ pub(crate) enum ReplyType {
     ERR_SHUTDOWN = (1 << 31) + 7,
     ERR_BLOCK_SIZE_REQD = (1 << 31) + 8,
     ERR_TOO_BIG = (1 << 31) + 9,
+    ERR_EXT_HEADER_REQD = (1 << 31) + 10,
 }
 
 /// Builder for replying to an option
 #[must_use]
 pub(crate) struct OptReply {
-    opt: OptType,
-    reply_type: ReplyType,
-    data: Vec<u8>,
+    pub opt: OptType,
+    pub reply_type: ReplyType,
+    pub data: Vec<u8>,
 }
 
 impl OptReply {
@@ -154,6 +180,45 @@ impl OptReply {
         stream.flush()?;
         Ok(())
     }
+
+    /// Length of the header that precedes the reply data.
+    pub const HEADER_LEN: usize = 20;
+
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let (opt, reply_type, len) = Self::get_header(stream)?;
+        let mut data = vec![0u8; len as usize];
+        stream
+            .read_exact(&mut data)
+            .wrap_err_with(|| format!("reading {reply_type:?} reply to {opt:?}"))?;
+        Ok(Self {
+            opt,
+            reply_type,
+            data,
+        })
+    }
+
+    /// Read the header of an option reply, returning the option, the reply
+    /// type, and the length of the data that follows.
+    pub fn get_header<IO: Read>(stream: &mut IO) -> Result<(OptType, ReplyType, u32)> {
+        let magic = stream.read_u64::<BE>()?;
+        if magic != REPLY_MAGIC {
+            bail!(ProtocolError(format!(
+                "unexpected option reply magic {magic}"
+            )));
+        }
+        let opt = stream.read_u32::<BE>()?;
+        let opt = OptType::try_from(opt)
+            .map_err(|_| ProtocolError(format!("reply to unexpected option {opt}")))?;
+        let reply_type = stream.read_u32::<BE>()?;
+        let reply_type = ReplyType::try_from(reply_type)
+            .map_err(|_| ProtocolError(format!("unexpected reply type {reply_type}")))?;
+        let len = stream.read_u32::<BE>()?;
+        ensure!(
+            len < 10_000,
+            ProtocolError(format!("option reply length {len} is too large"))
+        );
+        Ok((opt, reply_type, len))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -163,7 +228,21 @@ pub(crate) struct Opt {
 }
 
 impl Opt {
+    /// Length of the header that precedes the option data.
+    pub const HEADER_LEN: usize = 16;
+
     pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let (typ, option_len) = Self::get_header(stream)?;
+        let mut data = vec![0u8; option_len as usize];
+        stream
+            .read_exact(&mut data)
+            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
+        Ok(Self { typ, data })
+    }
+
+    /// Read the header of an option, returning its type and the length of the
+    /// data that follows.
+    pub fn get_header<IO: Read>(stream: &mut IO) -> Result<(OptType, u32)> {
         // C: 64 bits, 0x49484156454F5054 (ASCII 'IHAVEOPT') (note same newstyle handshake's magic number)
         // C: 32 bits, option
         // C: 32 bits, length of option data (unsigned)
@@ -180,11 +259,7 @@ impl Opt {
             option_len < 10_000,
             ProtocolError(format!("option length {option_len} is too large"))
         );
-        let mut data = vec![0u8; option_len as usize];
-        stream
-            .read_exact(&mut data)
-            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
-        Ok(Self { typ, data })
+        Ok((typ, option_len))
     }
 
     pub fn put<IO: Write>(self, stream: &mut IO) -> Result<()> {
@@ -199,34 +274,64 @@ impl Opt {
 /// Builder for reply to a OptType::LIST option request
 #[must_use]
 pub(crate) struct ExportList {
-    export_names: Vec<String>,
+    // export names and descriptions
+    exports: Vec<(String, Option<String>)>,
 }
 
 impl ExportList {
-    pub fn new(export_names: Vec<String>) -> Self {
-        Self { export_names }
+    pub fn new(exports: Vec<(String, Option<String>)>) -> Self {
+        Self { exports }
     }
+
     pub fn put<IO: Write>(self, stream: &mut IO) -> Result<()> {
         // Return zero or more NBD_REP_SERVER replies, one for each export,
         // followed by NBD_REP_ACK or an error (such as NBD_REP_ERR_SHUTDOWN).
         // The server MAY omit entries from this list if TLS has not been
         // negotiated, the server is operating in SELECTIVETLS mode, and the
         // entry concerned is a TLS-only export.
-        for name in self.export_names.into_iter() {
+        for (name, description) in self.exports.into_iter() {
             let mut data = vec![];
             data.write_u32::<BE>(name.len() as u32)?;
             data.write_all(name.as_bytes())?;
+            if let Some(description) = description {
+                data.write_all(description.as_bytes())?;
+            }
             OptReply::new(OptType::LIST, ReplyType::SERVER, data).put(stream)?;
-            OptReply::ack(OptType::LIST).put(stream)?;
         }
+        OptReply::ack(OptType::LIST).put(stream)?;
         Ok(())
     }
+
+    /// Parse the data of an `NBD_REP_SERVER` reply into an export name and
+    /// description.
+    pub fn get_entry(mut data: &[u8]) -> Result<(String, Option<String>)> {
+        // S: 32 bits, length of name (unsigned); MUST NOT be greater than the
+        //    option data length - 4
+        // S: name of the export, as expected by NBD_OPT_EXPORT_NAME, NBD_OPT_INFO,
+        //    or NBD_OPT_GO
+        // S: [optional] details about the export, in a human-readable form
+        let len = data.read_u32::<BE>()? as usize;
+        ensure!(
+            len <= data.len(),
+            ProtocolError(format!("export name length {len} is too large"))
+        );
+        let (name, description) = data.split_at(len);
+        let name = String::from_utf8(name.to_vec())
+            .wrap_err(ProtocolError::new("invalid UTF-8 in export name"))?;
+        let description = if description.is_empty() {
+            None
+        } else {
+            Some(
+                String::from_utf8(description.to_vec())
+                    .wrap_err(ProtocolError::new("invalid UTF-8 in export description"))?,
+            )
+        };
+        Ok((name, description))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct InfoRequest {
-    // we just ignore the requested export names in general
-    #[allow(dead_code)]
     pub name: String,
     pub typs: Vec<InfoType>,
 }
@@ -248,6 +353,266 @@ impl InfoRequest {
         }
         Ok(InfoRequest { name, typs })
     }
+
+    pub fn put<IO: Write>(&self, stream: &mut IO) -> io::Result<()> {
+        stream.write_u32::<BE>(self.name.len() as u32)?;
+        stream.write_all(self.name.as_bytes())?;
+        stream.write_u16::<BE>(self.typs.len() as u16)?;
+        for &typ in &self.typs {
+            stream.write_u16::<BE>(typ.into())?;
+        }
+        Ok(())
+    }
+}
+
+/// Largest maximum block size supported by this crate, which is also the
+/// default maximum the protocol recommends.
+pub const MAX_BLOCK_SIZE: u32 = 32 * 1024 * 1024;
+
+/// Block size constraints of an export, as sent in `NBD_INFO_BLOCK_SIZE`.
+///
+/// The offset and length of requests must be multiples of `minimum`, and reads
+/// and writes can be at most `maximum` bytes. Requests of `preferred` size
+/// avoid inefficiencies such as read-modify-write cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizes {
+    /// Minimum block size, a power of two up to 64 KiB.
+    pub minimum: u32,
+    /// Preferred block size, a power of two of at least `minimum`.
+    pub preferred: u32,
+    /// Maximum size of a read or write, a multiple of `minimum` of at least
+    /// `preferred`.
+    pub maximum: u32,
+}
+
+impl Default for BlockSizes {
+    /// No alignment requirements, and the largest supported maximum.
+    fn default() -> Self {
+        Self {
+            minimum: 1,
+            preferred: 4096,
+            maximum: MAX_BLOCK_SIZE,
+        }
+    }
+}
+
+impl BlockSizes {
+    /// Check the rules of the protocol, which any server's constraints
+    /// follow: the minimum and preferred sizes are powers of two, and neither
+    /// the preferred nor the maximum size is below the minimum.
+    ///
+    /// Unlike [`BlockSizes::validate`], this allows maximums above
+    /// [`MAX_BLOCK_SIZE`].
+    pub fn validate_protocol(&self) -> Result<()> {
+        let Self {
+            minimum,
+            preferred,
+            maximum,
+        } = *self;
+        ensure!(
+            minimum.is_power_of_two() && preferred.is_power_of_two(),
+            "block sizes {minimum} and {preferred} must be powers of two"
+        );
+        ensure!(
+            minimum <= preferred && minimum <= maximum,
+            "minimum block size {minimum} is larger than {preferred} or {maximum}"
+        );
+        Ok(())
+    }
+
+    /// Check that the constraints are consistent, and that this crate supports
+    /// them as a server.
+    pub fn validate(&self) -> Result<()> {
+        let Self {
+            minimum,
+            preferred,
+            maximum,
+        } = *self;
+        ensure!(
+            minimum.is_power_of_two() && minimum <= 64 * 1024,
+            "minimum block size {minimum} must be a power of two up to 64 KiB"
+        );
+        ensure!(
+            preferred.is_power_of_two() && preferred >= minimum,
+            "preferred block size {preferred} must be a power of two of at least {minimum}"
+        );
+        ensure!(
+            maximum.is_multiple_of(minimum) && maximum >= preferred,
+            "maximum block size {maximum} must be a multiple of {minimum} of at least {preferred}"
+        );
+        ensure!(
+            maximum <= MAX_BLOCK_SIZE,
+            "maximum block size {maximum} is larger than {MAX_BLOCK_SIZE}"
+        );
+        Ok(())
+    }
+
+    /// Check if a request for `len` bytes at `off` meets the minimum block
+    /// size.
+    pub fn aligned(&self, off: u64, len: u64) -> bool {
+        let minimum = self.minimum as u64;
+        off.is_multiple_of(minimum) && len.is_multiple_of(minimum)
+    }
+}
+
+/// Information about an export, from an `NBD_REP_INFO` reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Info {
+    Export { size: u64, flags: u16 },
+    Name(String),
+    Description(String),
+    BlockSize(BlockSizes),
+}
+
+impl Info {
+    /// Parse the data of an `NBD_REP_INFO` reply, returning None for
+    /// information types this crate does not know.
+    pub fn get(mut data: &[u8]) -> Result<Option<Self>> {
+        let typ = data.read_u16::<BE>()?;
+        let Ok(typ) = InfoType::try_from(typ) else {
+            return Ok(None);
+        };
+        let info = match typ {
+            InfoType::EXPORT => Info::Export {
+                size: data.read_u64::<BE>()?,
+                flags: data.read_u16::<BE>()?,
+            },
+            InfoType::NAME => Info::Name(
+                String::from_utf8(data.to_vec())
+                    .wrap_err(ProtocolError::new("invalid UTF-8 in export name"))?,
+            ),
+            InfoType::DESCRIPTION => Info::Description(
+                String::from_utf8(data.to_vec())
+                    .wrap_err(ProtocolError::new("invalid UTF-8 in export description"))?,
+            ),
+            InfoType::BLOCK_SIZE => Info::BlockSize(BlockSizes {
+                minimum: data.read_u32::<BE>()?,
+                preferred: data.read_u32::<BE>()?,
+                maximum: data.read_u32::<BE>()?,
+            }),
+        };
+        Ok(Some(info))
+    }
+}
+
+/// Body of an `NBD_OPT_LIST_META_CONTEXT` or `NBD_OPT_SET_META_CONTEXT` option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MetaContextRequest {
+    pub name: String,
+    pub queries: Vec<String>,
+}
+
+impl MetaContextRequest {
+    fn get_string<IO: Read>(stream: &mut IO) -> Result<String> {
+        let len = stream.read_u32::<BE>()?;
+        ensure!(
+            len < 10_000,
+            ProtocolError(format!("string length {len} is too large"))
+        );
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf)?;
+        let s = String::from_utf8(buf).wrap_err(ProtocolError::new("invalid UTF-8 string"))?;
+        Ok(s)
+    }
+
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        // C: 32 bits, length of export name.
+        // C: String, name of export for which we wish to list metadata contexts.
+        // C: 32 bits, number of queries
+        // C: 32 bits, length of query
+        // C: String, query to list a subset of the available metadata contexts.
+        let name = Self::get_string(stream)?;
+        let num_queries = stream.read_u32::<BE>()?;
+        let mut queries = vec![];
+        for _ in 0..num_queries {
+            queries.push(Self::get_string(stream)?);
+        }
+        Ok(Self { name, queries })
+    }
+
+    pub fn put<IO: Write>(&self, stream: &mut IO) -> Result<()> {
+        stream.write_u32::<BE>(self.name.len() as u32)?;
+        stream.write_all(self.name.as_bytes())?;
+        stream.write_u32::<BE>(self.queries.len() as u32)?;
+        for query in &self.queries {
+            stream.write_u32::<BE>(query.len() as u32)?;
+            stream.write_all(query.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the metadata context describing allocation status.
+pub const BASE_ALLOCATION: &str = "base:allocation";
+
+bitflags! {
+    /// Status flags for extents in the `base:allocation` metadata context.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct AllocationFlags: u32 {
+        /// The extent is not allocated (reading it returns zeroes, unless
+        /// `ZERO` is clear).
+        const HOLE = 1 << 0;
+        /// The extent reads as all zeroes.
+        const ZERO = 1 << 1;
+    }
+}
+
+/// Prefix of the names of metadata contexts reporting the blocks written
+/// since a checkpoint, which are `qemu:dirty-bitmap:<checkpoint>`.
+pub const QEMU_DIRTY_BITMAP: &str = "qemu:dirty-bitmap:";
+
+bitflags! {
+    /// Status flags for extents in a [`QEMU_DIRTY_BITMAP`] metadata context.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct DirtyBitmapFlags: u32 {
+        /// The extent has been written since the checkpoint.
+        const DIRTY = 1 << 0;
+    }
+}
+
+/// A range of an export with uniform status, as reported by
+/// `NBD_CMD_BLOCK_STATUS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// Length of the extent in bytes.
+    pub length: u64,
+    /// Status flags, whose meaning depends on the metadata context (see
+    /// [`AllocationFlags`] for [`BASE_ALLOCATION`]).
+    pub flags: u32,
+}
+
+impl Extent {
+    /// An extent of allocated data.
+    pub fn data(length: u64) -> Self {
+        Self { length, flags: 0 }
+    }
+
+    /// An unallocated extent that reads as zeroes.
+    pub fn hole(length: u64) -> Self {
+        let flags = AllocationFlags::HOLE | AllocationFlags::ZERO;
+        Self {
+            length,
+            flags: flags.bits(),
+        }
+    }
+
+    /// An allocated extent that reads as zeroes.
+    pub fn zero(length: u64) -> Self {
+        Self {
+            length,
+            flags: AllocationFlags::ZERO.bits(),
+        }
+    }
+
+    /// Interpret the flags as `base:allocation` flags.
+    pub fn allocation(&self) -> AllocationFlags {
+        AllocationFlags::from_bits_truncate(self.flags)
+    }
+
+    /// Interpret the flags as dirty bitmap flags.
+    pub fn dirty_bitmap(&self) -> DirtyBitmapFlags {
+        DirtyBitmapFlags::from_bits_truncate(self.flags)
+    }
 }
 
 // -------------------
@@ -288,8 +653,9 @@ pub(crate) struct Request {
     pub typ: Cmd,
     pub handle: u64,
     pub offset: u64,
-    // used for READ (redundant for WRITE)
-    pub len: u32,
+    // used for READ (redundant for WRITE); only 32 bits unless extended
+    // headers are in use
+    pub len: u64,
     // actual data is stored into caller-provided buffer
     pub data_len: usize,
 }
@@ -306,6 +672,7 @@ impl fmt::Debug for Request {
             || self.typ == Cmd::WRITE
             || self.typ == Cmd::TRIM
             || self.typ == Cmd::CACHE
+            || self.typ == Cmd::BLOCK_STATUS
         {
             f = f.field("offset", &self.offset);
         }
@@ -317,7 +684,7 @@ impl fmt::Debug for Request {
 }
 
 impl Request {
-    pub fn new(typ: Cmd, offset: u64, len: u32) -> Self {
+    pub fn new(typ: Cmd, offset: u64, len: u64) -> Self {
         let handle = rand::thread_rng().gen::<u64>();
         let data_len = if typ == Cmd::WRITE { len as usize } else { 0 };
         Request {
@@ -334,25 +701,83 @@ impl Request {
     ///
     /// data (required only for a Cmd::WRITE) is not part of a Request and must
     /// be included separately.
-    pub fn put<IO: Write>(&self, data: &[u8], stream: &mut IO) -> Result<()> {
+    ///
+    /// `extended` selects the extended request header, which must be used if
+    /// and only if extended headers were negotiated.
+    pub fn put<IO: Write>(&self, data: &[u8], stream: &mut IO, extended: bool) -> Result<()> {
         assert!(
             self.data_len <= data.len(),
             "not enough data passed for request {} > {}",
             self.data_len,
             data.len(),
         );
-        stream.write_u32::<BE>(REQUEST_MAGIC)?;
+        if extended {
+            stream.write_u32::<BE>(EXTENDED_REQUEST_MAGIC)?;
+        } else {
+            stream.write_u32::<BE>(REQUEST_MAGIC)?;
+        }
         stream.write_u16::<BE>(self.flags.bits())?;
         stream.write_u16::<BE>(self.typ.into())?;
         stream.write_u64::<BE>(self.handle)?;
         stream.write_u64::<BE>(self.offset)?;
-        stream.write_u32::<BE>(self.len)?;
+        if extended {
+            stream.write_u64::<BE>(self.len)?;
+        } else {
+            let len = u32::try_from(self.len).wrap_err_with(|| {
+                ProtocolError(format!("length {} requires extended headers", self.len))
+            })?;
+            stream.write_u32::<BE>(len)?;
+        }
         stream.write_all(&data[..self.data_len])?;
         Ok(())
     }
 
     /// Get reads the next request, storing the data for a write request in buf.
-    pub fn get<IO: Read>(stream: &mut IO, buf: &mut [u8]) -> Result<Self> {
+    ///
+    /// If the data does not fit in buf, the rest of it is discarded, so
+    /// `data_len` is smaller than `len`.
+    ///
+    /// `extended` is whether extended headers were negotiated.
+    pub fn get<IO: Read>(stream: &mut IO, buf: &mut [u8], extended: bool) -> Result<Self> {
+        let mut req = Self::get_header(stream, extended)?;
+        if req.typ == Cmd::WRITE {
+            req.get_data(stream, buf)?;
+        }
+        Ok(req)
+    }
+
+    /// Read the data of a write request (after its header) into buf, setting
+    /// `data_len`.
+    ///
+    /// If the data does not fit in buf, the rest of it is discarded.
+    pub fn get_data<IO: Read>(&mut self, stream: &mut IO, buf: &mut [u8]) -> Result<()> {
+        self.data_len = (self.len.min(buf.len() as u64)) as usize;
+        stream
+            .read_exact(&mut buf[..self.data_len])
+            .wrap_err_with(|| format!("parsing write request of length {}", self.data_len))?;
+        let extra = self.len - self.data_len as u64;
+        if extra > 0 {
+            // keep the stream in sync for the next request
+            let discarded = io::copy(&mut stream.take(extra), &mut io::sink())?;
+            if discarded < extra {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the request header, which precedes the data of a write.
+    pub fn header_len(extended: bool) -> usize {
+        if extended {
+            32
+        } else {
+            28
+        }
+    }
+
+    /// Read the header of a request, without the data of a write (so
+    /// `data_len` is 0).
+    pub fn get_header<IO: Read>(stream: &mut IO, extended: bool) -> Result<Self> {
         // C: 32 bits, 0x25609513, magic (NBD_REQUEST_MAGIC)
         // C: 16 bits, command flags
         // C: 16 bits, type
@@ -360,8 +785,16 @@ impl Request {
         // C: 64 bits, offset (unsigned)
         // C: 32 bits, length (unsigned)
         // C: (length bytes of data if the request is of type NBD_CMD_WRITE)
+        //
+        // With extended headers, the magic is 0x21e41c71
+        // (NBD_EXTENDED_REQUEST_MAGIC) and the length is 64 bits.
         let magic = stream.read_u32::<BE>()?;
-        if magic != REQUEST_MAGIC {
+        let expected_magic = if extended {
+            EXTENDED_REQUEST_MAGIC
+        } else {
+            REQUEST_MAGIC
+        };
+        if magic != expected_magic {
             bail!(ProtocolError(format!("wrong request magic {}", magic)));
         }
         let flags = stream.read_u16::<BE>()?;
@@ -372,15 +805,10 @@ impl Request {
             Cmd::try_from(typ).map_err(|_| ProtocolError(format!("unexpected command {}", typ)))?;
         let handle = stream.read_u64::<BE>()?;
         let offset = stream.read_u64::<BE>()?;
-        let len = stream.read_u32::<BE>()?;
-        let data_len;
-        if typ == Cmd::WRITE {
-            data_len = (len as usize).min(buf.len());
-            stream
-                .read_exact(&mut buf[..data_len])
-                .wrap_err_with(|| format!("parsing write request of length {data_len}"))?;
+        let len = if extended {
+            stream.read_u64::<BE>()?
         } else {
-            data_len = 0;
+            stream.read_u32::<BE>()? as u64
         };
         Ok(Self {
             flags,
@@ -388,7 +816,7 @@ impl Request {
             handle,
             offset,
             len,
-            data_len,
+            data_len: 0,
         })
     }
 }
@@ -413,6 +841,9 @@ impl ErrorType {
             ErrorKind::PermissionDenied => Self::EPERM,
             ErrorKind::InvalidInput => Self::EOVERFLOW,
             ErrorKind::UnexpectedEof => Self::EOVERFLOW,
+            ErrorKind::Unsupported => Self::ENOTSUP,
+            ErrorKind::StorageFull => Self::ENOSPC,
+            ErrorKind::OutOfMemory => Self::ENOMEM,
             _ => {
                 warn!("unexpected error {}", kind);
                 Self::EIO
@@ -450,28 +881,6 @@ impl<'a> SimpleReply<'a> {
         }
     }
 
-    pub fn get<IO: Read>(stream: &mut IO, buf: &'a mut [u8]) -> Result<Self> {
-        let mut magic_buf = [0u8; 4];
-        let n = stream.read(&mut magic_buf)?;
-        if n == 0 {
-            error!("socket is closed for reading");
-        }
-        let magic = u32::from_be_bytes(magic_buf);
-        if magic != SIMPLE_REPLY_MAGIC {
-            bail!(ProtocolError::new(format!("wrong reply magic {magic}")));
-        }
-        let err = stream.read_u32::<BE>()?;
-        let err = ErrorType::try_from(err)
-            .map_err(|_| ProtocolError::new(format!("invalid error type {err}")))?;
-        let handle = stream.read_u64::<BE>()?;
-        stream.read_exact(buf)?;
-        Ok(Self {
-            err,
-            handle,
-            data: buf,
-        })
-    }
-
     pub fn put<IO: Write>(self, stream: &mut IO) -> Result<()> {
         // The simple reply message MUST be sent by the server in response to all requests if structured replies have not been negotiated using NBD_OPT_STRUCTURED_REPLY. If structured replies have been negotiated, a simple reply MAY be used as a reply to any request other than NBD_CMD_READ, but only if the reply has no data payload. The message looks as follows:
         //
@@ -487,6 +896,435 @@ impl<'a> SimpleReply<'a> {
     }
 }
 
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub(crate) struct ReplyFlags: u16 {
+        const DONE = 1 << 0;
+    }
+}
+
+#[derive(IntoPrimitive, TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub(crate) enum ReplyChunkType {
+    NONE = 0,
+    OFFSET_DATA = 1,
+    OFFSET_HOLE = 2,
+    BLOCK_STATUS = 5,
+    BLOCK_STATUS_EXT = 6,
+    ERROR = (1 << 15) + 1,
+    ERROR_OFFSET = (1 << 15) + 2,
+}
+
+/// Builder for one chunk of a structured reply.
+///
+/// The payload is split into fixed-size `fields` (built by the constructors)
+/// followed by variable-length `data`, so that read data does not need to be
+/// copied.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct StructuredReply<'a> {
+    pub flags: ReplyFlags,
+    pub typ: ReplyChunkType,
+    pub handle: u64,
+    // offset of the request, echoed in extended reply headers
+    pub req_offset: u64,
+    pub fields: Vec<u8>,
+    pub data: &'a [u8],
+}
+
+impl<'a> StructuredReply<'a> {
+    fn new(req: &Request, typ: ReplyChunkType, fields: Vec<u8>, data: &'a [u8]) -> Self {
+        StructuredReply {
+            flags: ReplyFlags::empty(),
+            typ,
+            handle: req.handle,
+            req_offset: req.offset,
+            fields,
+            data,
+        }
+    }
+
+    /// A chunk with no payload, used to finish a reply.
+    pub fn none(req: &Request) -> Self {
+        Self::new(req, ReplyChunkType::NONE, vec![], &[])
+    }
+
+    pub fn offset_data(req: &Request, offset: u64, data: &'a [u8]) -> Self {
+        // 64 bits: offset (unsigned)
+        // length - 8 bytes: data
+        Self::new(
+            req,
+            ReplyChunkType::OFFSET_DATA,
+            offset.to_be_bytes().to_vec(),
+            data,
+        )
+    }
+
+    pub fn offset_hole(req: &Request, offset: u64, len: u32) -> Self {
+        // 64 bits: offset (unsigned)
+        // 32 bits: hole size (unsigned, MUST be nonzero)
+        let mut fields = offset.to_be_bytes().to_vec();
+        fields.extend_from_slice(&len.to_be_bytes());
+        Self::new(req, ReplyChunkType::OFFSET_HOLE, fields, &[])
+    }
+
+    /// Report the status of a range for one metadata context.
+    ///
+    /// With extended headers, the 64-bit `NBD_REPLY_TYPE_BLOCK_STATUS_EXT`
+    /// format is used; otherwise extent lengths must fit in 32 bits.
+    pub fn block_status(
+        req: &Request,
+        context_id: u32,
+        extents: &[Extent],
+        extended: bool,
+    ) -> Self {
+        // 32 bits: metadata context ID
+        // 32 bits: number of descriptors (only for NBD_REPLY_TYPE_BLOCK_STATUS_EXT)
+        //
+        // followed by a list of descriptors, each of which is either
+        // 32 bits: length of the extent (unsigned, MUST be nonzero)
+        // 32 bits: status flags
+        //
+        // or for NBD_REPLY_TYPE_BLOCK_STATUS_EXT
+        // 64 bits: length of the extent (unsigned, MUST be nonzero)
+        // 64 bits: status flags
+        let mut fields = context_id.to_be_bytes().to_vec();
+        if extended {
+            fields.extend_from_slice(&(extents.len() as u32).to_be_bytes());
+            for extent in extents {
+                fields.extend_from_slice(&extent.length.to_be_bytes());
+                fields.extend_from_slice(&(extent.flags as u64).to_be_bytes());
+            }
+            Self::new(req, ReplyChunkType::BLOCK_STATUS_EXT, fields, &[])
+        } else {
+            for extent in extents {
+                fields.extend_from_slice(&(extent.length as u32).to_be_bytes());
+                fields.extend_from_slice(&extent.flags.to_be_bytes());
+            }
+            Self::new(req, ReplyChunkType::BLOCK_STATUS, fields, &[])
+        }
+    }
+
+    fn error_fields(err: ErrorType, msg: &str) -> Vec<u8> {
+        // 32 bits: error (MUST be nonzero)
+        // 16 bits: message length (no more than header length - 6)
+        // message length bytes: optional string suitable for direct display
+        // to a human being
+        let mut fields = u32::from(err).to_be_bytes().to_vec();
+        fields.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        fields.extend_from_slice(msg.as_bytes());
+        fields
+    }
+
+    pub fn error(req: &Request, err: ErrorType, msg: &str) -> Self {
+        Self::new(
+            req,
+            ReplyChunkType::ERROR,
+            Self::error_fields(err, msg),
+            &[],
+        )
+    }
+
+    pub fn error_offset(req: &Request, err: ErrorType, offset: u64, msg: &str) -> Self {
+        // same as NBD_REPLY_TYPE_ERROR, followed by
+        // 64 bits: offset (unsigned)
+        let mut fields = Self::error_fields(err, msg);
+        fields.extend_from_slice(&offset.to_be_bytes());
+        Self::new(req, ReplyChunkType::ERROR_OFFSET, fields, &[])
+    }
+
+    /// Mark this as the final chunk of the reply.
+    pub fn done(mut self) -> Self {
+        self.flags |= ReplyFlags::DONE;
+        self
+    }
+
+    /// Send this chunk, using an extended reply header if `extended`.
+    pub fn put<IO: Write>(self, stream: &mut IO, extended: bool) -> Result<()> {
+        // S: 32 bits, 0x668e33ef, magic (NBD_STRUCTURED_REPLY_MAGIC)
+        // S: 16 bits, flags
+        // S: 16 bits, type
+        // S: 64 bits, handle
+        // S: 32 bits, length of payload (unsigned)
+        // S: length bytes of payload data (if length is nonzero)
+        //
+        // The extended reply header instead uses magic 0x6e8a278c
+        // (NBD_EXTENDED_REPLY_MAGIC), includes the 64-bit offset of the
+        // request after the handle, and has a 64-bit payload length.
+        let len = self.fields.len() + self.data.len();
+        if extended {
+            stream.write_u32::<BE>(EXTENDED_REPLY_MAGIC)?;
+        } else {
+            stream.write_u32::<BE>(STRUCTURED_REPLY_MAGIC)?;
+        }
+        stream.write_u16::<BE>(self.flags.bits())?;
+        stream.write_u16::<BE>(self.typ.into())?;
+        stream.write_u64::<BE>(self.handle)?;
+        if extended {
+            stream.write_u64::<BE>(self.req_offset)?;
+            stream.write_u64::<BE>(len as u64)?;
+        } else {
+            stream.write_u32::<BE>(len as u32)?;
+        }
+        stream.write_all(&self.fields)?;
+        stream.write_all(self.data)?;
+        Ok(())
+    }
+}
+
+/// Parsed payload of a structured reply chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ChunkPayload {
+    None,
+    OffsetData {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    OffsetHole {
+        offset: u64,
+        len: u32,
+    },
+    BlockStatus {
+        context_id: u32,
+        extents: Vec<Extent>,
+    },
+    Error {
+        err: ErrorType,
+        msg: String,
+        offset: Option<u64>,
+    },
+}
+
+/// One chunk of a structured reply, as received by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReplyChunk {
+    pub flags: ReplyFlags,
+    pub handle: u64,
+    pub payload: ChunkPayload,
+}
+
+impl ReplyChunk {
+    pub fn is_done(&self) -> bool {
+        self.flags.contains(ReplyFlags::DONE)
+    }
+
+    fn get_payload(typ: ReplyChunkType, payload: &mut &[u8]) -> Result<ChunkPayload> {
+        let payload = match typ {
+            ReplyChunkType::NONE => ChunkPayload::None,
+            ReplyChunkType::OFFSET_DATA => {
+                let offset = payload.read_u64::<BE>()?;
+                ChunkPayload::OffsetData {
+                    offset,
+                    data: payload.to_vec(),
+                }
+            }
+            ReplyChunkType::OFFSET_HOLE => {
+                let offset = payload.read_u64::<BE>()?;
+                let len = payload.read_u32::<BE>()?;
+                ChunkPayload::OffsetHole { offset, len }
+            }
+            ReplyChunkType::BLOCK_STATUS => {
+                let context_id = payload.read_u32::<BE>()?;
+                let mut extents = vec![];
+                while !payload.is_empty() {
+                    let length = payload.read_u32::<BE>()? as u64;
+                    let flags = payload.read_u32::<BE>()?;
+                    extents.push(Extent { length, flags });
+                }
+                ChunkPayload::BlockStatus {
+                    context_id,
+                    extents,
+                }
+            }
+            ReplyChunkType::BLOCK_STATUS_EXT => {
+                let context_id = payload.read_u32::<BE>()?;
+                let count = payload.read_u32::<BE>()?;
+                let mut extents = vec![];
+                for _ in 0..count {
+                    let length = payload.read_u64::<BE>()?;
+                    let flags = payload.read_u64::<BE>()? as u32;
+                    extents.push(Extent { length, flags });
+                }
+                ChunkPayload::BlockStatus {
+                    context_id,
+                    extents,
+                }
+            }
+            ReplyChunkType::ERROR | ReplyChunkType::ERROR_OFFSET => {
+                let err = payload.read_u32::<BE>()?;
+                // The client SHOULD treat an unexpected error value as if it
+                // were NBD_EINVAL.
+                let err = ErrorType::try_from(err).unwrap_or(ErrorType::EINVAL);
+                let msg_len = payload.read_u16::<BE>()?;
+                let mut msg = vec![0u8; msg_len as usize];
+                payload.read_exact(&mut msg)?;
+                let msg = String::from_utf8_lossy(&msg).to_string();
+                let offset = if typ == ReplyChunkType::ERROR_OFFSET {
+                    Some(payload.read_u64::<BE>()?)
+                } else {
+                    None
+                };
+                ChunkPayload::Error { err, msg, offset }
+            }
+        };
+        Ok(payload)
+    }
+}
+
+/// A reply received by the client, either a simple reply or a single chunk of
+/// a structured reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Reply {
+    /// A simple reply. For a successful read, the data follows and must be
+    /// read separately since its length is not part of the reply.
+    Simple {
+        err: ErrorType,
+        handle: u64,
+    },
+    Chunk(ReplyChunk),
+}
+
+impl Reply {
+    pub fn handle(&self) -> u64 {
+        match self {
+            Reply::Simple { handle, .. } => *handle,
+            Reply::Chunk(chunk) => chunk.handle,
+        }
+    }
+
+    /// Read a reply, refusing a payload longer than `max_payload` returns for
+    /// its header (which guards against a server that sends a huge length).
+    pub fn get<IO: Read>(
+        stream: &mut IO,
+        max_payload: impl FnOnce(&ReplyHeader) -> Result<usize>,
+    ) -> Result<Self> {
+        let header = ReplyHeader::get(stream)?;
+        header.check_payload_len(max_payload(&header)?)?;
+        let mut payload = vec![0u8; header.payload_len()];
+        stream
+            .read_exact(&mut payload)
+            .wrap_err_with(|| format!("reading payload of {header:?}"))?;
+        Self::from_header(header, &payload)
+    }
+
+    /// Parse a reply from its header and payload.
+    pub fn from_header(header: ReplyHeader, mut payload: &[u8]) -> Result<Self> {
+        match header {
+            ReplyHeader::Simple { err, handle } => Ok(Reply::Simple { err, handle }),
+            ReplyHeader::Chunk {
+                flags, typ, handle, ..
+            } => {
+                let payload = ReplyChunk::get_payload(typ, &mut payload)
+                    .wrap_err_with(|| ProtocolError(format!("malformed {typ:?} chunk")))?;
+                Ok(Reply::Chunk(ReplyChunk {
+                    flags,
+                    handle,
+                    payload,
+                }))
+            }
+        }
+    }
+}
+
+/// The header of a [`Reply`], which precedes the payload of a chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ReplyHeader {
+    Simple {
+        err: ErrorType,
+        handle: u64,
+    },
+    Chunk {
+        flags: ReplyFlags,
+        typ: ReplyChunkType,
+        handle: u64,
+        len: u32,
+    },
+}
+
+impl ReplyHeader {
+    /// Length of the longest header (an extended reply).
+    pub const MAX_LEN: usize = 32;
+
+    /// Length of the header that starts with `magic` (including the magic).
+    pub fn header_len(magic: u32) -> Result<usize> {
+        match magic {
+            SIMPLE_REPLY_MAGIC => Ok(16),
+            STRUCTURED_REPLY_MAGIC => Ok(20),
+            EXTENDED_REPLY_MAGIC => Ok(Self::MAX_LEN),
+            _ => bail!(ProtocolError::new(format!("wrong reply magic {magic}"))),
+        }
+    }
+
+    /// Length of the payload after this header. The data of a simple reply
+    /// to a read is not included, since its length is not in the header.
+    pub fn payload_len(&self) -> usize {
+        match self {
+            ReplyHeader::Simple { .. } => 0,
+            ReplyHeader::Chunk { len, .. } => *len as usize,
+        }
+    }
+
+    pub fn handle(&self) -> u64 {
+        match self {
+            ReplyHeader::Simple { handle, .. } | ReplyHeader::Chunk { handle, .. } => *handle,
+        }
+    }
+
+    /// Check that the payload is at most `max` bytes long.
+    pub fn check_payload_len(&self, max: usize) -> Result<()> {
+        let len = self.payload_len();
+        ensure!(
+            len <= max,
+            ProtocolError(format!(
+                "reply payload length {len} is too large for request {}",
+                self.handle()
+            ))
+        );
+        Ok(())
+    }
+
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let magic = stream.read_u32::<BE>()?;
+        match magic {
+            SIMPLE_REPLY_MAGIC => {
+                let err = stream.read_u32::<BE>()?;
+                let err = ErrorType::try_from(err)
+                    .map_err(|_| ProtocolError::new(format!("invalid error type {err}")))?;
+                let handle = stream.read_u64::<BE>()?;
+                Ok(ReplyHeader::Simple { err, handle })
+            }
+            STRUCTURED_REPLY_MAGIC | EXTENDED_REPLY_MAGIC => {
+                let flags = stream.read_u16::<BE>()?;
+                let flags = ReplyFlags::from_bits(flags)
+                    .ok_or_else(|| ProtocolError(format!("unexpected reply flags {flags}")))?;
+                let typ = stream.read_u16::<BE>()?;
+                let typ = ReplyChunkType::try_from(typ)
+                    .map_err(|_| ProtocolError(format!("unexpected reply chunk type {typ}")))?;
+                let handle = stream.read_u64::<BE>()?;
+                let len = if magic == EXTENDED_REPLY_MAGIC {
+                    // the request offset is redundant for the client
+                    let _offset = stream.read_u64::<BE>()?;
+                    let len = stream.read_u64::<BE>()?;
+                    ensure!(
+                        len <= u32::MAX as u64,
+                        ProtocolError(format!("reply payload length {len} is too large"))
+                    );
+                    len as u32
+                } else {
+                    stream.read_u32::<BE>()?
+                };
+                Ok(ReplyHeader::Chunk {
+                    flags,
+                    typ,
+                    handle,
+                    len,
+                })
+            }
+            _ => bail!(ProtocolError::new(format!("wrong reply magic {magic}"))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -503,6 +1341,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_block_sizes_validate() {
+        let sizes = BlockSizes {
+            minimum: 512,
+            preferred: 4096,
+            maximum: u32::MAX,
+        };
+        // allowed by the protocol, but too large for this crate's server
+        assert!(sizes.validate_protocol().is_ok());
+        assert!(sizes.validate().is_err());
+        for (minimum, preferred, maximum) in [(3, 4096, 4096), (512, 1000, 4096), (4096, 512, 4096)]
+        {
+            let sizes = BlockSizes {
+                minimum,
+                preferred,
+                maximum,
+            };
+            assert!(sizes.validate_protocol().is_err(), "{sizes:?}");
+        }
+    }
+
     #[test]
     fn test_request_get_put_read() -> Result<()> {
         let req = Request {
@@ -514,8 +1373,8 @@ mod tests {
             data_len: 0,
         };
         let mut buf = vec![];
-        req.put(&[], &mut buf)?;
-        assert_eq!(Request::get(&mut &buf[..], &mut [])?, req);
+        req.put(&[], &mut buf, false)?;
+        assert_eq!(Request::get(&mut &buf[..], &mut [], false)?, req);
         Ok(())
     }
 
@@ -531,10 +1390,61 @@ mod tests {
         };
         let data = vec![1; 12];
         let mut buf = vec![];
-        req.put(&data, &mut buf)?;
+        req.put(&data, &mut buf, false)?;
         let mut data_read = vec![0; 12];
-        assert_eq!(Request::get(&mut &buf[..], &mut data_read)?, req);
+        assert_eq!(Request::get(&mut &buf[..], &mut data_read, false)?, req);
         assert_eq!(data, data_read);
         Ok(())
     }
+
+    #[test]
+    fn test_request_get_put_extended() -> Result<()> {
+        let req = Request::new(Cmd::TRIM, 1 << 40, 5 << 40);
+        let mut buf = vec![];
+        assert!(req.put(&[], &mut buf, false).is_err());
+        buf.clear();
+        req.put(&[], &mut buf, true)?;
+        assert_eq!(Request::get(&mut &buf[..], &mut [], true)?, req);
+        assert!(Request::get(&mut &buf[..], &mut [], false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_structured_reply_put_get() -> Result<()> {
+        let req = Request::new(Cmd::READ, 4096, 8);
+        let mut buf = vec![];
+        StructuredReply::offset_data(&req, 4096, &[1, 2, 3]).put(&mut buf, false)?;
+        StructuredReply::error_offset(&req, ErrorType::EIO, 4099, "bad sector")
+            .done()
+            .put(&mut buf, true)?;
+        let mut stream = &buf[..];
+        assert_eq!(
+            Reply::get(&mut stream, |_| Ok(usize::MAX))?,
+            Reply::Chunk(ReplyChunk {
+                flags: ReplyFlags::empty(),
+                handle: req.handle,
+                payload: ChunkPayload::OffsetData {
+                    offset: 4096,
+                    data: vec![1, 2, 3]
+                },
+            })
+        );
+        assert_eq!(
+            Reply::get(&mut stream, |_| Ok(usize::MAX))?,
+            Reply::Chunk(ReplyChunk {
+                flags: ReplyFlags::DONE,
+                handle: req.handle,
+                payload: ChunkPayload::Error {
+                    err: ErrorType::EIO,
+                    msg: "bad sector".to_string(),
+                    offset: Some(4099),
+                },
+            })
+        );
+        assert!(stream.is_empty());
+
+        // payloads longer than the caller allows are refused
+        assert!(Reply::get(&mut &buf[..], |_| Ok(10)).is_err());
+        Ok(())
+    }
 }
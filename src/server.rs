@@ -1,27 +1,45 @@
 //! Network Block Device server, exporting an underlying file.
 //!
-//! Implements the most basic parts of the protocol: a single export,
-//! read/write/flush commands, and no other flags (eg, read-only or TLS
-//! support).
+//! Implements the most basic parts of the protocol: named (and possibly
+//! read-only) exports, read/write/flush commands, structured replies and
+//! extended headers, block status through the `base:allocation` metadata
+//! context, and TLS.
+//!
+//! With the `tokio` feature, the server can also handle clients on a tokio
+//! runtime (see `Server::handle_client_async`), with exports that implement
+//! `AsyncBlocks`.
 //!
 //! See <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md> for
 //! the protocol description.
 
 #![deny(missing_docs)]
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, prelude::*};
-use std::net::TcpListener;
-use std::os::fd::AsRawFd;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
 use std::os::unix::fs::FileExt;
-use std::sync::{Arc, Mutex};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
 use std::thread;
 
-use byteorder::{ReadBytesExt, WriteBytesExt, BE};
-use color_eyre::eyre::{bail, WrapErr};
+use byteorder::{WriteBytesExt, BE};
+use color_eyre::eyre::{bail, ensure, eyre, WrapErr};
 use color_eyre::Result;
 use log::{info, warn};
 
+#[cfg(feature = "tokio")]
+pub use async_trait::async_trait;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "tokio")]
+use crate::connection::Tokio;
+use crate::connection::{block_on, Blocking, Connection};
 use crate::proto::*;
+use crate::tls::{ServerTls, TlsPolicy};
 
 /// Blocks is a byte array that can be exported by this server, with a basic
 /// read/write API that works on arbitrary offsets.
@@ -41,6 +59,195 @@ pub trait Blocks {
 
     /// Flush any outstanding writes to stable storage.
     fn flush(&self) -> io::Result<()>;
+
+    /// Describe which parts of the `len` bytes starting at `off` are holes or
+    /// read as zeroes, as consecutive extents starting at `off`.
+    ///
+    /// The extents may cover less than the whole range, but must cover at
+    /// least its start. The default implementation reports the whole range as
+    /// allocated data.
+    fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let _ = off;
+        Ok(vec![Extent::data(len)])
+    }
+
+    /// Write zeroes to `len` bytes starting at `off`.
+    ///
+    /// If `punch_hole` is set, the range may be deallocated instead, as long as
+    /// it reads as zeroes afterward. If `fast` is set and zeroing is not
+    /// faster than writing zeroes, fail with [`io::ErrorKind::Unsupported`]
+    /// instead of doing it slowly.
+    ///
+    /// The default implementation writes buffers of zeroes (so it is never
+    /// fast).
+    fn write_zeroes(&self, off: u64, len: u64, punch_hole: bool, fast: bool) -> io::Result<()> {
+        let _ = punch_hole;
+        if fast {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        write_zero_buffers(self, off, len)
+    }
+
+    /// Discard `len` bytes starting at `off`, which the client no longer
+    /// needs. The range may read as anything afterward.
+    ///
+    /// Discarding is advisory, so the default implementation does nothing.
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
+        Ok(())
+    }
+
+    /// Prepare to read `len` bytes starting at `off` soon, for example by
+    /// loading them into a cache.
+    ///
+    /// The default implementation does nothing, which is appropriate for
+    /// Blocks that are already in memory.
+    fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
+        Ok(())
+    }
+
+    /// Change the size of this array to `size` bytes. Growing it adds bytes
+    /// that read as zeroes.
+    ///
+    /// The default implementation fails with [`io::ErrorKind::Unsupported`].
+    fn resize(&self, size: u64) -> io::Result<()> {
+        let _ = size;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Check if [`Blocks::resize`] is supported, so that the server offers
+    /// `NBD_CMD_RESIZE` to clients. The default is false.
+    fn can_resize(&self) -> bool {
+        false
+    }
+
+    /// Check if [`Blocks::flush`] persists every completed write, no matter
+    /// which connection made it, so that clients can safely spread their
+    /// requests over several connections.
+    ///
+    /// All connections to an export share its Blocks, so this only fails if
+    /// writes are buffered in a way that flush does not see. The default is
+    /// false, to be safe.
+    fn can_multi_conn(&self) -> bool {
+        false
+    }
+}
+
+/// Plan how to write zeroes to `len` bytes at `off` with ordinary writes,
+/// returning a buffer of zeroes and the offset and length of each write.
+fn zero_buffer_writes(off: u64, len: u64) -> (Vec<u8>, impl Iterator<Item = (u64, usize)>) {
+    let size = (READ_BLOCK_SIZE * 16).min(len as usize);
+    let writes = (off..off + len)
+        .step_by(size.max(1))
+        .map(move |pos| (pos, size.min((off + len - pos) as usize)));
+    (vec![0u8; size], writes)
+}
+
+/// Write zeroes with ordinary writes.
+fn write_zero_buffers<B: Blocks + ?Sized>(blocks: &B, off: u64, len: u64) -> io::Result<()> {
+    let (zeroes, writes) = zero_buffer_writes(off, len);
+    for (pos, n) in writes {
+        blocks.write_at(&zeroes[..n], pos)?;
+    }
+    Ok(())
+}
+
+/// Call `fallocate`, returning false if the file does not support `mode`.
+fn fallocate(file: &File, mode: libc::c_int, off: u64, len: u64) -> io::Result<bool> {
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            off as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// Write zeroes to part of a file (see [`Blocks::write_zeroes`]), preferring
+/// `fallocate`, which is called like the function of the same name.
+pub(crate) fn zero_file_range<B: Blocks + ?Sized>(
+    blocks: &B,
+    fallocate: impl Fn(libc::c_int, u64, u64) -> io::Result<bool>,
+    off: u64,
+    len: u64,
+    punch_hole: bool,
+    fast: bool,
+) -> io::Result<()> {
+    let keep_size = libc::FALLOC_FL_KEEP_SIZE;
+    if punch_hole && fallocate(libc::FALLOC_FL_PUNCH_HOLE | keep_size, off, len)? {
+        return Ok(());
+    }
+    if fallocate(libc::FALLOC_FL_ZERO_RANGE | keep_size, off, len)? {
+        return Ok(());
+    }
+    if fast {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    write_zero_buffers(blocks, off, len)
+}
+
+/// Add an extent to the end of a list, merging it with the previous extent if
+/// they have the same status.
+fn push_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    if extent.length == 0 {
+        return;
+    }
+    match extents.last_mut() {
+        Some(last) if last.flags == extent.flags => last.length += extent.length,
+        _ => extents.push(extent),
+    }
+}
+
+/// Find the holes in a file using `lseek` with `SEEK_DATA` and `SEEK_HOLE`.
+fn file_extents(file: &File, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+    let fd = file.as_raw_fd();
+    let end = off + len;
+    let mut extents = vec![];
+    let mut pos = off;
+    while pos < end {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // there is no data after pos
+                Some(libc::ENXIO) => {
+                    push_extent(&mut extents, Extent::hole(end - pos));
+                    break;
+                }
+                // the file system cannot report holes
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => {
+                    push_extent(&mut extents, Extent::data(end - pos));
+                    break;
+                }
+                _ => return Err(err),
+            }
+        }
+        let data = data as u64;
+        if data > pos {
+            let hole_end = data.min(end);
+            push_extent(&mut extents, Extent::hole(hole_end - pos));
+            pos = hole_end;
+            continue;
+        }
+        let hole = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let data_end = (hole as u64).min(end);
+        push_extent(&mut extents, Extent::data(data_end - pos));
+        pos = data_end;
+    }
+    Ok(extents)
 }
 
 impl Blocks for File {
@@ -59,23 +266,124 @@ impl Blocks for File {
     fn flush(&self) -> io::Result<()> {
         self.sync_all()
     }
+
+    fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        file_extents(self, off, len)
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, punch_hole: bool, fast: bool) -> io::Result<()> {
+        let fallocate = |mode, off, len| fallocate(self, mode, off, len);
+        zero_file_range(self, fallocate, off, len, punch_hole, fast)
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        // if the file system cannot punch holes, there is nothing to do
+        fallocate(
+            self,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            off,
+            len,
+        )?;
+        Ok(())
+    }
+
+    fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        fadvise_willneed(self, off, len)
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn can_resize(&self) -> bool {
+        true
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        // fsync covers all writes to the file
+        true
+    }
+}
+
+/// Ask the kernel to read part of a file into the page cache, without waiting
+/// for it.
+fn fadvise_willneed(file: &File, off: u64, len: u64) -> io::Result<()> {
+    let ret = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            off as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+/// Zero a buffer, giving whole pages back to the operating system.
+///
+/// The pages are still mapped and read as zeroes, but no longer use memory
+/// until they are written again.
+fn discard_memory(buf: &mut [u8]) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let addr = buf.as_mut_ptr() as usize;
+    let start = (addr.next_multiple_of(page_size) - addr).min(buf.len());
+    let end = start.max(((addr + buf.len()) / page_size * page_size).saturating_sub(addr));
+    if end > start {
+        let ret = unsafe {
+            libc::madvise(
+                buf[start..end].as_mut_ptr() as *mut libc::c_void,
+                end - start,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if ret == 0 {
+            // only the partial pages at the edges are left
+            buf[..start].fill(0);
+            buf[end..].fill(0);
+            return;
+        }
+    }
+    buf.fill(0);
 }
 
 /// MemBlocks is a convenience for an in-memory implementation of Blocks using
 /// an array of bytes.
 #[derive(Debug, Clone)]
-pub struct MemBlocks(Arc<Mutex<Vec<u8>>>);
+pub struct MemBlocks {
+    data: Arc<Mutex<Vec<u8>>>,
+    // largest size that resize can grow the array to
+    max_size: usize,
+}
+
+/// The size a [`MemBlocks`] can grow to by default (unless it starts larger).
+pub const DEFAULT_MEM_MAX_SIZE: usize = 1024 * 1024 * 1024;
 
 impl MemBlocks {
     /// Create a new MemBlocks from an in-memory array.
+    ///
+    /// Clients can grow it (with `NBD_CMD_RESIZE`) to
+    /// [`DEFAULT_MEM_MAX_SIZE`] or its initial size, whichever is larger.
     pub fn new(data: Vec<u8>) -> Self {
-        MemBlocks(Arc::new(Mutex::new(data)))
+        let max_size = data.len().max(DEFAULT_MEM_MAX_SIZE);
+        Self::with_max_size(data, max_size)
+    }
+
+    /// Create a new MemBlocks from an in-memory array, which can grow to at
+    /// most `max_size` bytes.
+    pub fn with_max_size(data: Vec<u8>, max_size: usize) -> Self {
+        MemBlocks {
+            data: Arc::new(Mutex::new(data)),
+            max_size,
+        }
     }
 }
 
 impl Blocks for MemBlocks {
     fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
-        let data = self.0.lock().unwrap();
+        let data = self.data.lock().unwrap();
         let off = off as usize;
         if off + buf.len() > data.len() {
             return Err(io::Error::new(
@@ -88,7 +396,7 @@ impl Blocks for MemBlocks {
     }
 
     fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
-        let mut data = self.0.lock().unwrap();
+        let mut data = self.data.lock().unwrap();
         let off = off as usize;
         if off + buf.len() > data.len() {
             return Err(io::Error::new(
@@ -101,13 +409,94 @@ impl Blocks for MemBlocks {
     }
 
     fn size(&self) -> io::Result<u64> {
-        let data = self.0.lock().unwrap();
+        let data = self.data.lock().unwrap();
         Ok(data.len() as u64)
     }
 
     fn flush(&self) -> io::Result<()> {
         Ok(())
     }
+
+    fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let data = self.data.lock().unwrap();
+        let (off, end) = (off as usize, (off + len) as usize);
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "out-of-bounds block status",
+            ));
+        }
+        // report aligned blocks of zeroes
+        let mut extents = vec![];
+        let mut start = off;
+        while start < end {
+            let block_end = ((start / READ_BLOCK_SIZE + 1) * READ_BLOCK_SIZE).min(end);
+            let len = (block_end - start) as u64;
+            if data[start..block_end].iter().all(|&b| b == 0) {
+                push_extent(&mut extents, Extent::zero(len));
+            } else {
+                push_extent(&mut extents, Extent::data(len));
+            }
+            start = block_end;
+        }
+        Ok(extents)
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, punch_hole: bool, _fast: bool) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let (off, end) = (off as usize, (off + len) as usize);
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "out-of-bounds write",
+            ));
+        }
+        if punch_hole {
+            discard_memory(&mut data[off..end]);
+        } else {
+            data[off..end].fill(0);
+        }
+        Ok(())
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let (off, end) = (off as usize, (off + len) as usize);
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "out-of-bounds trim",
+            ));
+        }
+        discard_memory(&mut data[off..end]);
+        Ok(())
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        if size > self.max_size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "size is larger than the maximum",
+            ));
+        }
+        let mut data = self.data.lock().unwrap();
+        let size = size as usize;
+        if let Some(additional) = size.checked_sub(data.len()) {
+            data.try_reserve_exact(additional)
+                .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        }
+        data.resize(size, 0);
+        Ok(())
+    }
+
+    fn can_resize(&self) -> bool {
+        true
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        // writes are immediately visible, and there is nothing to flush
+        true
+    }
 }
 
 /// `Device` abstracts over a raw block device.
@@ -117,10 +506,23 @@ pub struct Device(File);
 impl Device {
     /// Creates a new `Device` backed by the given file.
     ///
-    /// The file should be opened with read/write permissions.
+    /// The file should be opened with read/write permissions, unless it is
+    /// only used for a read-only export.
     pub fn new(inner: File) -> Self {
         Self(inner)
     }
+
+    /// Open the block device at path, only for reading if `read_only`.
+    pub fn open<P: AsRef<std::path::Path>>(path: P, read_only: bool) -> io::Result<Self> {
+        let file = File::options().read(true).write(!read_only).open(path)?;
+        Ok(Self(file))
+    }
+}
+
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
 }
 
 // Ioctl constants for BLKGETSIZE64.
@@ -137,6 +539,24 @@ nix::ioctl_read!(
     u64
 );
 
+// Ioctl wrapper for BLKZEROOUT (also defined in linux/fs.h), which takes the
+// start and length of the range to zero.
+nix::ioctl_write_ptr_bad!(
+    /// Zeroes a range of a block device.
+    blkdev_zeroout,
+    nix::request_code_none!(BLKGETSIZE64_IOC_MAGIC, 127),
+    [u64; 2]
+);
+
+// Ioctl wrapper for BLKDISCARD (also defined in linux/fs.h), which takes the
+// start and length of the range to discard.
+nix::ioctl_write_ptr_bad!(
+    /// Discards a range of a block device.
+    blkdev_discard,
+    nix::request_code_none!(BLKGETSIZE64_IOC_MAGIC, 119),
+    [u64; 2]
+);
+
 impl Blocks for Device {
     fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
         FileExt::read_exact_at(&self.0, buf, off)
@@ -153,182 +573,1153 @@ impl Blocks for Device {
                 .map_err(|_| io::Error::last_os_error())?;
             (ret == 0)
                 .then_some(size)
-                .ok_or_else(|| io::Error::last_os_error())
+                .ok_or_else(io::Error::last_os_error)
         }
     }
 
     fn flush(&self) -> io::Result<()> {
         self.0.sync_all()
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use color_eyre::Result;
-
-    use super::{Blocks, MemBlocks};
 
-    #[test]
-    fn test_mem_blocks() -> Result<()> {
-        let data = vec![1u8; 10];
-        let file = MemBlocks::new(data);
+    fn write_zeroes(&self, off: u64, len: u64, punch_hole: bool, fast: bool) -> io::Result<()> {
+        if fast {
+            // For block devices, punching a hole never falls back to writing
+            // zeroes, unlike BLKZEROOUT.
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            if punch_hole && fallocate(&self.0, mode, off, len)? {
+                return Ok(());
+            }
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let range = [off, len];
+        unsafe { blkdev_zeroout(self.0.as_raw_fd(), &range) }
+            .map_err(|errno| io::Error::from_raw_os_error(errno as i32))?;
+        Ok(())
+    }
 
-        let mut buf = [0u8; 3];
-        file.read_at(&mut buf, 7)?;
-        assert_eq!(buf, [1, 1, 1]);
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let range = [off, len];
+        match unsafe { blkdev_discard(self.0.as_raw_fd(), &range) } {
+            Ok(_) => Ok(()),
+            // the device does not support discard
+            Err(nix::errno::Errno::EOPNOTSUPP) => Ok(()),
+            Err(errno) => Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
 
-        file.write_at(&[3, 4], 8)?;
+    fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        fadvise_willneed(&self.0, off, len)
+    }
 
-        file.read_at(&mut buf, 7)?;
-        assert_eq!(buf, [1, 3, 4]);
-        Ok(())
+    fn can_multi_conn(&self) -> bool {
+        // fsync covers all writes to the device
+        true
     }
 }
 
-/// Wrap a Blocks and implement the core NBD operations using its operations.
-#[derive(Debug)]
-struct Export<F: Blocks>(F);
+/// An async variant of [`Blocks`], for backends that wait for I/O without
+/// blocking a thread (for example, network storage).
+///
+/// Implementations use the [`macro@async_trait`] attribute. Exports with async
+/// Blocks are added with [`Server::add_async_export`], and work best with
+/// [`Server::handle_client_async`]; connections handled with
+/// [`Server::handle_client`] run the futures on the runtime the export was
+/// added in.
+#[cfg(feature = "tokio")]
+#[async_trait]
+pub trait AsyncBlocks: Send + Sync {
+    /// Fill buf starting from off (reading `buf.len()` bytes)
+    async fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()>;
 
-impl<F: Blocks> Export<F> {
-    /// Name returns the name of the single, default export, for listing purposes.
-    ///
-    /// The server ignores all export names anyway so this name is not important.
-    fn name(&self) -> String {
-        "default".to_string()
+    /// Write data from buf to self starting at off (writing `buf.len()` bytes)
+    async fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()>;
+
+    /// Get the size of this array (in bytes)
+    async fn size(&self) -> io::Result<u64>;
+
+    /// Flush any outstanding writes to stable storage.
+    async fn flush(&self) -> io::Result<()>;
+
+    /// Describe which parts of the `len` bytes starting at `off` are holes or
+    /// read as zeroes (see [`Blocks::extents`]).
+    async fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let _ = off;
+        Ok(vec![Extent::data(len)])
     }
 
-    fn read<'a>(
+    /// Write zeroes to `len` bytes starting at `off` (see
+    /// [`Blocks::write_zeroes`]).
+    ///
+    /// The default implementation writes buffers of zeroes (so it is never
+    /// fast).
+    async fn write_zeroes(
         &self,
         off: u64,
-        len: u32,
-        buf: &'a mut [u8],
-    ) -> core::result::Result<&'a mut [u8], ErrorType> {
-        let len = len as usize;
-        if buf.len() < len {
-            return Err(ErrorType::EOVERFLOW);
+        len: u64,
+        punch_hole: bool,
+        fast: bool,
+    ) -> io::Result<()> {
+        let _ = punch_hole;
+        if fast {
+            return Err(io::ErrorKind::Unsupported.into());
         }
-        let buf = &mut buf[..len];
-        match Blocks::read_at(&self.0, buf, off) {
-            Ok(_) => Ok(buf),
-            Err(err) => Err(ErrorType::from_io_kind(err.kind())),
+        let (zeroes, writes) = zero_buffer_writes(off, len);
+        for (pos, n) in writes {
+            self.write_at(&zeroes[..n], pos).await?;
         }
+        Ok(())
     }
 
-    fn write(&self, off: u64, len: usize, data: &[u8]) -> core::result::Result<(), ErrorType> {
-        if len > data.len() {
-            return Err(ErrorType::EOVERFLOW);
-        }
-        let data = &data[..len];
-        Blocks::write_at(&self.0, data, off).map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+    /// Discard `len` bytes starting at `off` (see [`Blocks::trim`]).
+    ///
+    /// Discarding is advisory, so the default implementation does nothing.
+    async fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
         Ok(())
     }
 
-    fn flush(&self) -> io::Result<()> {
-        self.0.flush()?;
+    /// Prepare to read `len` bytes starting at `off` soon (see
+    /// [`Blocks::prefetch`]).
+    ///
+    /// The default implementation does nothing.
+    async fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
         Ok(())
     }
 
-    fn size(&self) -> io::Result<u64> {
-        self.0.size()
+    /// Change the size of this array to `size` bytes (see
+    /// [`Blocks::resize`]).
+    ///
+    /// The default implementation fails with [`io::ErrorKind::Unsupported`].
+    async fn resize(&self, size: u64) -> io::Result<()> {
+        let _ = size;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Check if [`AsyncBlocks::resize`] is supported (see
+    /// [`Blocks::can_resize`]).
+    fn can_resize(&self) -> bool {
+        false
+    }
+
+    /// Check if [`AsyncBlocks::flush`] persists every completed write, no
+    /// matter which connection made it (see [`Blocks::can_multi_conn`]).
+    fn can_multi_conn(&self) -> bool {
+        false
     }
 }
 
-#[derive(Debug)]
-struct ServerInner<F: Blocks> {
-    export: Export<F>,
+/// The Blocks of an export, which are either blocking or async.
+enum Backend {
+    Blocking(Arc<dyn Blocks + Send + Sync>),
+    /// Async Blocks, and the runtime for connections handled outside of one.
+    #[cfg(feature = "tokio")]
+    Async(Box<dyn AsyncBlocks>, tokio::runtime::Handle),
 }
 
-impl<F: Blocks> ServerInner<F> {
-    // fake constant for the server's supported operations
-    #[allow(non_snake_case)]
-    fn TRANSMIT_FLAGS() -> TransmitFlags {
-        TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH | TransmitFlags::SEND_FUA
+/// Check if the caller is within a tokio runtime, where blocking Blocks must
+/// not run directly.
+fn in_runtime() -> bool {
+    #[cfg(feature = "tokio")]
+    return tokio::runtime::Handle::try_current().is_ok();
+    #[cfg(not(feature = "tokio"))]
+    false
+}
+
+/// Run an operation on blocking Blocks. Within a tokio runtime (on the async
+/// server), it runs on the runtime's blocking threads so that it does not
+/// stall the other connections.
+async fn run_blocking<T: Send + 'static>(
+    blocks: &Arc<dyn Blocks + Send + Sync>,
+    op: impl FnOnce(&dyn Blocks) -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    #[cfg(feature = "tokio")]
+    if in_runtime() {
+        let blocks = Arc::clone(blocks);
+        return tokio::task::spawn_blocking(move || op(&*blocks))
+            .await
+            .map_err(io::Error::other)?;
     }
+    op(&**blocks)
+}
 
-    // Agree on basic negotiation flags.
-    fn initial_handshake<IO: Read + Write>(stream: &mut IO) -> Result<HandshakeFlags> {
-        stream.write_u64::<BE>(MAGIC)?;
-        stream.write_u64::<BE>(IHAVEOPT)?;
-        stream
-            .write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
-        let client_flags = stream.read_u32::<BE>()?;
-        let client_flags = ClientHandshakeFlags::from_bits(client_flags)
-            .ok_or_else(|| ProtocolError::new(format!("unexpected client flags {client_flags}")))?;
-        if !client_flags.contains(ClientHandshakeFlags::C_FIXED_NEWSTYLE) {
-            bail!(ProtocolError::new("client does not support FIXED_NEWSTYLE"));
+/// Run a future from async Blocks. Outside of a tokio runtime (on the blocking
+/// server), it runs on the runtime the export was added in, which provides
+/// tokio's I/O and timers.
+#[cfg(feature = "tokio")]
+async fn run_async<T>(
+    runtime: &tokio::runtime::Handle,
+    fut: impl std::future::Future<Output = T>,
+) -> T {
+    if in_runtime() {
+        fut.await
+    } else {
+        runtime.block_on(fut)
+    }
+}
+
+impl Backend {
+    async fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        match self {
+            // the blocking threads need their own buffer
+            Backend::Blocking(blocks) if in_runtime() => {
+                let len = buf.len();
+                let data = run_blocking(blocks, move |blocks| {
+                    let mut data = vec![0u8; len];
+                    blocks.read_at(&mut data, off).map(|()| data)
+                })
+                .await?;
+                buf.copy_from_slice(&data);
+                Ok(())
+            }
+            Backend::Blocking(blocks) => blocks.read_at(buf, off),
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.read_at(buf, off)).await,
         }
-        let mut flags = HandshakeFlags::FIXED_NEWSTYLE;
-        if client_flags.contains(ClientHandshakeFlags::C_NO_ZEROES) {
-            flags |= HandshakeFlags::NO_ZEROES;
+    }
+
+    async fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) if in_runtime() => {
+                let data = buf.to_vec();
+                run_blocking(blocks, move |blocks| blocks.write_at(&data, off)).await
+            }
+            Backend::Blocking(blocks) => blocks.write_at(buf, off),
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.write_at(buf, off)).await,
         }
-        Ok(flags)
     }
 
-    fn send_export_list<IO: Write>(&self, stream: &mut IO) -> Result<()> {
-        ExportList::new(vec![self.export.name()]).put(stream)?;
-        Ok(())
+    async fn size(&self) -> io::Result<u64> {
+        match self {
+            Backend::Blocking(blocks) => run_blocking(blocks, move |blocks| blocks.size()).await,
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.size()).await,
+        }
     }
 
-    /// Send export info at the end of newstyle negotiation, when client sends NBD_OPT_EXPORT_NAME.
-    fn send_export_info<IO: Write>(&self, stream: &mut IO, flags: HandshakeFlags) -> Result<()> {
-        // If the value of the option field is `NBD_OPT_EXPORT_NAME` and the
-        // server is willing to allow the export, the server replies with
-        // information about the used export:
-        //
-        // S: 64 bits, size of the export in bytes (unsigned)
-        // S: 16 bits, transmission flags
-        // S: 124 bytes, zeroes (reserved) (unless `NBD_FLAG_C_NO_ZEROES` was negotiated by the client)
-        stream.write_u64::<BE>(self.export.size()?)?;
-        let transmit = Self::TRANSMIT_FLAGS();
-        stream.write_u16::<BE>(transmit.bits())?;
-        if !flags.contains(HandshakeFlags::NO_ZEROES) {
-            stream.write_all(&[0u8; 124])?;
+    async fn flush(&self) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) => run_blocking(blocks, move |blocks| blocks.flush()).await,
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.flush()).await,
+        }
+    }
+
+    async fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        match self {
+            Backend::Blocking(blocks) => {
+                run_blocking(blocks, move |blocks| blocks.extents(off, len)).await
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.extents(off, len)).await,
         }
-        stream.flush()?;
-        Ok(())
     }
 
-    fn info_responses<IO: Write>(
+    async fn write_zeroes(
         &self,
-        opt_typ: OptType,
-        info_req: InfoRequest,
-        stream: &mut IO,
-    ) -> Result<()> {
-        for typ in info_req.typs.iter().chain([InfoType::EXPORT].iter()) {
-            match typ {
-                InfoType::EXPORT => {
-                    // Mandatory information before a successful completion of
-                    // NBD_OPT_INFO or NBD_OPT_GO. Describes the same
-                    // information that is sent in response to the older
-                    // NBD_OPT_EXPORT_NAME, except that there are no trailing
-                    // zeroes whether or not NBD_FLAG_C_NO_ZEROES was
-                    // negotiated. length MUST be 12, and the reply payload is
-                    // interpreted as follows:
-                    //
-                    // - 16 bits, NBD_INFO_EXPORT
-                    // - 64 bits, size of the export in bytes (unsigned)
-                    // - 16 bits, transmission flags
-                    let mut buf = vec![];
-                    buf.write_u16::<BE>(InfoType::EXPORT.into())?;
-                    buf.write_u64::<BE>(self.export.size()?)?;
-                    buf.write_u16::<BE>(Self::TRANSMIT_FLAGS().bits())?;
-                    OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
-                }
-                InfoType::BLOCK_SIZE => {
-                    // Represents the server's advertised block size
-                    // constraints; see the "Block size constraints" section for
-                    // more details on what these values represent, and on
-                    // constraints on their values. The server MUST send this
-                    // info if it is requested and it intends to enforce block
-                    // size constraints other than the defaults. After sending
-                    // this information in response to an NBD_OPT_GO in which
-                    // the client specifically requested NBD_INFO_BLOCK_SIZE,
-                    // the server can legitimately assume that any client that
-                    // continues the session will support the block size
-                    // constraints supplied (note that this assumption cannot be
-                    // made solely on the basis of an NBD_OPT_INFO with an
-                    // NBD_INFO_BLOCK_SIZE request, or an NBD_OPT_GO without an
-                    // explicit NBD_INFO_BLOCK_SIZE request). The length MUST be
+        off: u64,
+        len: u64,
+        punch_hole: bool,
+        fast: bool,
+    ) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) => {
+                run_blocking(blocks, move |blocks| {
+                    blocks.write_zeroes(off, len, punch_hole, fast)
+                })
+                .await
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => {
+                run_async(runtime, blocks.write_zeroes(off, len, punch_hole, fast)).await
+            }
+        }
+    }
+
+    async fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) => {
+                run_blocking(blocks, move |blocks| blocks.trim(off, len)).await
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.trim(off, len)).await,
+        }
+    }
+
+    async fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) => {
+                run_blocking(blocks, move |blocks| blocks.prefetch(off, len)).await
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.prefetch(off, len)).await,
+        }
+    }
+
+    async fn resize(&self, size: u64) -> io::Result<()> {
+        match self {
+            Backend::Blocking(blocks) => {
+                run_blocking(blocks, move |blocks| blocks.resize(size)).await
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, runtime) => run_async(runtime, blocks.resize(size)).await,
+        }
+    }
+
+    fn can_resize(&self) -> bool {
+        match self {
+            Backend::Blocking(blocks) => blocks.can_resize(),
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, _) => blocks.can_resize(),
+        }
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        match self {
+            Backend::Blocking(blocks) => blocks.can_multi_conn(),
+            #[cfg(feature = "tokio")]
+            Backend::Async(blocks, _) => blocks.can_multi_conn(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::{Blocks, DirtyBitmap, MemBlocks, DIRTY_BLOCK_SIZE};
+    use crate::proto::{DirtyBitmapFlags, Extent};
+    use crate::testing::TempFile;
+
+    #[test]
+    fn test_mem_blocks() -> Result<()> {
+        let data = vec![1u8; 10];
+        let file = MemBlocks::new(data);
+
+        let mut buf = [0u8; 3];
+        file.read_at(&mut buf, 7)?;
+        assert_eq!(buf, [1, 1, 1]);
+
+        file.write_at(&[3, 4], 8)?;
+
+        file.read_at(&mut buf, 7)?;
+        assert_eq!(buf, [1, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_blocks_extents() -> Result<()> {
+        let file = MemBlocks::new(vec![0u8; 4096 * 4]);
+        file.write_at(&[1], 4096 + 10)?;
+        assert_eq!(
+            file.extents(100, 4096 * 3)?,
+            [
+                Extent::zero(4096 - 100),
+                Extent::data(4096),
+                Extent::zero(4096 + 100)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dirty_bitmap() {
+        const B: u64 = DIRTY_BLOCK_SIZE;
+        let dirty = |length| Extent {
+            length,
+            flags: DirtyBitmapFlags::DIRTY.bits(),
+        };
+        let clean = |length| Extent { length, flags: 0 };
+        let mut bitmap = DirtyBitmap::default();
+        bitmap.mark(B + 1, 1);
+        bitmap.mark(B * 4, B);
+        assert_eq!(
+            bitmap.extents(0, B * 6),
+            [clean(B), dirty(B), clean(B * 2), dirty(B), clean(B)]
+        );
+        // overlapping and adjacent ranges merge
+        bitmap.mark(B * 2, B * 2);
+        assert_eq!(bitmap.dirty.len(), 1);
+        assert_eq!(
+            bitmap.extents(10, B * 6),
+            [clean(B - 10), dirty(B * 4), clean(B + 10)]
+        );
+        assert_eq!(bitmap.extents(B * 3, 10), [dirty(10)]);
+
+        // huge ranges are cheap to mark
+        bitmap.mark(0, 1 << 50);
+        assert_eq!(bitmap.dirty.len(), 1);
+        assert_eq!(bitmap.extents(B * 7, B), [dirty(B)]);
+    }
+
+    #[test]
+    fn test_file_write_zeroes() -> Result<()> {
+        let file = TempFile::new("zeroes")?;
+        file.write_at(&[1u8; 4096 * 4], 0)?;
+        let mut buf = vec![0u8; 4096 * 4];
+        for punch_hole in [true, false] {
+            file.write_zeroes(4096, 4096, punch_hole, false)?;
+            file.read_at(&mut buf, 0)?;
+            assert_eq!(buf[..4096], [1u8; 4096]);
+            assert_eq!(buf[4096..4096 * 2], [0u8; 4096]);
+            assert_eq!(buf[4096 * 2..], [1u8; 4096 * 2]);
+            file.write_at(&[1u8; 4096], 4096)?;
+        }
+        // zeroing must not change the size
+        file.write_zeroes(4096 * 3, 4096 * 2, true, false)?;
+        assert_eq!(file.size()?, 4096 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_blocks_trim() -> Result<()> {
+        let blocks = MemBlocks::new(vec![1u8; 1 << 20]);
+        blocks.trim(100, (1 << 20) - 200)?;
+        let mut buf = vec![0u8; 1 << 20];
+        blocks.read_at(&mut buf, 0)?;
+        assert_eq!(buf[..100], [1u8; 100]);
+        assert!(buf[100..(1 << 20) - 100].iter().all(|&b| b == 0));
+        assert_eq!(buf[(1 << 20) - 100..], [1u8; 100]);
+        // discarded memory can be written again
+        blocks.write_at(&[2u8; 10], 4096)?;
+        blocks.read_at(&mut buf[..12], 4095)?;
+        assert_eq!(buf[..12], [0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_extents() -> Result<()> {
+        let file = TempFile::new("extents")?;
+        file.set_len(1 << 20)?;
+        file.write_at(&[1u8; 4096], 1 << 19)?;
+        let extents = file.extents(0, 1 << 20)?;
+        assert_eq!(extents.iter().map(|e| e.length).sum::<u64>(), 1 << 20);
+        // the file system may not report holes, but the data must be reported
+        // as allocated
+        let mut off = 0;
+        for extent in extents {
+            if off <= 1 << 19 && 1 << 19 < off + extent.length {
+                assert_eq!(extent, Extent::data(extent.length));
+            }
+            off += extent.length;
+        }
+        Ok(())
+    }
+}
+
+// Granularity of dirty bitmaps, which is also qemu's default.
+const DIRTY_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Tracks which blocks of an export have been written since a checkpoint.
+#[derive(Debug, Default, Clone)]
+struct DirtyBitmap {
+    // dirty ranges of blocks (in units of DIRTY_BLOCK_SIZE), as the first
+    // block mapped to the block after the last, which neither overlap nor
+    // touch
+    dirty: BTreeMap<u64, u64>,
+}
+
+impl DirtyBitmap {
+    fn mark(&mut self, off: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut start = off / DIRTY_BLOCK_SIZE;
+        let mut end = (off + len - 1) / DIRTY_BLOCK_SIZE + 1;
+        // extend a range that starts earlier and reaches this one
+        if let Some((&prev_start, &prev_end)) = self.dirty.range(..start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+        // absorb the ranges that start within this one
+        let absorbed: Vec<u64> = self.dirty.range(start..=end).map(|(&s, _)| s).collect();
+        for range_start in absorbed {
+            let range_end = self.dirty.remove(&range_start).unwrap();
+            end = end.max(range_end);
+        }
+        self.dirty.insert(start, end);
+    }
+
+    fn extents(&self, off: u64, len: u64) -> Vec<Extent> {
+        let clean = |length| Extent { length, flags: 0 };
+        let dirty = |length| Extent {
+            length,
+            flags: DirtyBitmapFlags::DIRTY.bits(),
+        };
+        let end = off + len;
+        let first = off / DIRTY_BLOCK_SIZE;
+        let last = (end - 1) / DIRTY_BLOCK_SIZE;
+        // a range that starts before off may still cover it
+        let before = self.dirty.range(..first).next_back();
+        let mut extents = vec![];
+        let mut pos = off;
+        for (&range_start, &range_end) in before.into_iter().chain(self.dirty.range(first..=last)) {
+            let start = (range_start * DIRTY_BLOCK_SIZE).max(off);
+            let stop = range_end.saturating_mul(DIRTY_BLOCK_SIZE).min(end);
+            if stop <= start {
+                continue;
+            }
+            push_extent(&mut extents, clean(start - pos));
+            push_extent(&mut extents, dirty(stop - start));
+            pos = stop;
+        }
+        push_extent(&mut extents, clean(end - pos));
+        extents
+    }
+}
+
+/// The name of the export created by [`Server::new`].
+///
+/// Clients that request the empty export name also get this export.
+pub const DEFAULT_EXPORT: &str = "default";
+
+/// Settings for a single export.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// A human-readable description of the export, which clients can request
+    /// with `NBD_INFO_DESCRIPTION`.
+    pub description: Option<String>,
+    /// Block size constraints, which the server advertises with
+    /// `NBD_INFO_BLOCK_SIZE` and enforces on requests.
+    ///
+    /// With a minimum block size above 1, clients must ask for the constraints
+    /// before using the export with `NBD_OPT_GO`.
+    pub block_sizes: BlockSizes,
+    /// Refuse commands that modify the export, and advertise it as read-only.
+    ///
+    /// The Blocks of a read-only export are never written, so they can be
+    /// opened without write permission.
+    pub read_only: bool,
+    /// Whether clients must use TLS to access the export. Requiring TLS needs
+    /// a server created with TLS support.
+    pub tls: TlsPolicy,
+}
+
+/// Wrap a Blocks and implement the core NBD operations using its operations.
+struct Export {
+    // canonical name, which the empty name resolves to for the default export
+    name: String,
+    backend: Backend,
+    opts: ExportOptions,
+    // dirty bitmaps, by checkpoint name
+    checkpoints: Mutex<BTreeMap<String, DirtyBitmap>>,
+}
+
+impl std::fmt::Debug for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Export")
+            .field("name", &self.name)
+            .field("opts", &self.opts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Export {
+    fn new(name: &str, backend: Backend, opts: ExportOptions) -> Self {
+        Self {
+            name: name.to_string(),
+            backend,
+            opts,
+            checkpoints: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    async fn read<'a>(
+        &self,
+        off: u64,
+        len: u64,
+        buf: &'a mut [u8],
+    ) -> core::result::Result<&'a mut [u8], ErrorType> {
+        if (buf.len() as u64) < len {
+            return Err(ErrorType::EOVERFLOW);
+        }
+        let buf = &mut buf[..len as usize];
+        match self.backend.read_at(buf, off).await {
+            Ok(_) => Ok(buf),
+            Err(err) => Err(ErrorType::from_io_kind(err.kind())),
+        }
+    }
+
+    async fn write(
+        &self,
+        off: u64,
+        len: usize,
+        data: &[u8],
+    ) -> core::result::Result<(), ErrorType> {
+        if len > data.len() {
+            return Err(ErrorType::EOVERFLOW);
+        }
+        let data = &data[..len];
+        // mark first, so that even a partial write is tracked
+        self.mark_dirty(off, len as u64);
+        self.backend
+            .write_at(data, off)
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        Ok(())
+    }
+
+    async fn write_zeroes(
+        &self,
+        off: u64,
+        len: u64,
+        punch_hole: bool,
+        fast: bool,
+    ) -> core::result::Result<(), ErrorType> {
+        let size = self
+            .size()
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        if off.checked_add(len).is_none_or(|end| end > size) {
+            return Err(ErrorType::ENOSPC);
+        }
+        self.mark_dirty(off, len);
+        self.backend
+            .write_zeroes(off, len, punch_hole, fast)
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))
+    }
+
+    async fn trim(&self, off: u64, len: u64) -> core::result::Result<(), ErrorType> {
+        let size = self
+            .size()
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        if off.checked_add(len).is_none_or(|end| end > size) {
+            return Err(ErrorType::EINVAL);
+        }
+        // the contents are undefined afterward, so backups need the range
+        self.mark_dirty(off, len);
+        self.backend
+            .trim(off, len)
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))
+    }
+
+    async fn prefetch(&self, off: u64, len: u64) -> core::result::Result<(), ErrorType> {
+        let size = self
+            .size()
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        if off.checked_add(len).is_none_or(|end| end > size) {
+            return Err(ErrorType::EINVAL);
+        }
+        self.backend
+            .prefetch(off, len)
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))
+    }
+
+    async fn resize(&self, size: u64) -> core::result::Result<(), ErrorType> {
+        if !self.opts.block_sizes.aligned(0, size) {
+            return Err(ErrorType::EINVAL);
+        }
+        let old_size = self
+            .size()
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        self.backend
+            .resize(size)
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        // the blocks between the old and new size either appeared or
+        // disappeared
+        self.mark_dirty(old_size.min(size), old_size.abs_diff(size));
+        Ok(())
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.backend.flush().await
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        self.backend.size().await
+    }
+
+    /// Record a modification in the dirty bitmap of every checkpoint.
+    fn mark_dirty(&self, off: u64, len: u64) {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        for bitmap in checkpoints.values_mut() {
+            bitmap.mark(off, len);
+        }
+    }
+
+    fn checkpoint_names(&self) -> Vec<String> {
+        self.checkpoints.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// All of the metadata contexts supported by the export.
+    fn meta_contexts(&self) -> Vec<MetaContext> {
+        let mut contexts = vec![MetaContext::Allocation];
+        for name in self.checkpoint_names() {
+            contexts.push(MetaContext::DirtyBitmap(name));
+        }
+        contexts
+    }
+
+    /// Find the metadata contexts matching a query.
+    ///
+    /// When listing, a query of just a namespace (eg, `base:`) matches all of
+    /// the contexts in it.
+    fn match_meta_contexts(&self, query: &str, list: bool) -> Vec<MetaContext> {
+        self.meta_contexts()
+            .into_iter()
+            .filter(|context| {
+                let name = context.name();
+                name == query || (list && query.ends_with(':') && name.starts_with(query))
+            })
+            .collect()
+    }
+
+    /// Get the status in a metadata context for a block status request.
+    async fn extents(
+        &self,
+        context: &MetaContext,
+        off: u64,
+        len: u64,
+        req_one: bool,
+        extended: bool,
+    ) -> core::result::Result<Vec<Extent>, ErrorType> {
+        let size = self
+            .size()
+            .await
+            .map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        if len == 0 || off.checked_add(len).is_none_or(|end| end > size) {
+            return Err(ErrorType::EINVAL);
+        }
+        let context_extents = match context {
+            MetaContext::Allocation => self
+                .backend
+                .extents(off, len)
+                .await
+                .map_err(|err| ErrorType::from_io_kind(err.kind()))?,
+            MetaContext::DirtyBitmap(name) => {
+                let checkpoints = self.checkpoints.lock().unwrap();
+                // the checkpoint may have been removed since the client
+                // selected it
+                let bitmap = checkpoints.get(name).ok_or(ErrorType::EINVAL)?;
+                bitmap.extents(off, len)
+            }
+        };
+        let mut extents = vec![];
+        for extent in context_extents {
+            push_extent(&mut extents, extent);
+        }
+        if extents.is_empty() {
+            return Err(ErrorType::EIO);
+        }
+        if !extended {
+            // without extended headers, extent lengths are only 32 bits
+            if let Some(i) = extents.iter().position(|e| e.length > MAX_COMPACT_EXTENT) {
+                extents[i].length = MAX_COMPACT_EXTENT;
+                extents.truncate(i + 1);
+            }
+        }
+        if req_one {
+            extents.truncate(1);
+        }
+        Ok(extents)
+    }
+}
+
+/// A metadata context that a client can select with
+/// `NBD_OPT_SET_META_CONTEXT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MetaContext {
+    /// `base:allocation`, which reports holes and zeroes.
+    Allocation,
+    /// `qemu:dirty-bitmap:<checkpoint>`, which reports the blocks written
+    /// since a checkpoint.
+    DirtyBitmap(String),
+}
+
+impl MetaContext {
+    fn name(&self) -> String {
+        match self {
+            MetaContext::Allocation => BASE_ALLOCATION.to_string(),
+            MetaContext::DirtyBitmap(checkpoint) => format!("{QEMU_DIRTY_BITMAP}{checkpoint}"),
+        }
+    }
+}
+
+/// Options negotiated with a single client during the handshake.
+#[derive(Debug, Default, Clone)]
+struct Session {
+    // whether the connection has been upgraded to TLS
+    tls: bool,
+    structured_replies: bool,
+    // implies structured_replies
+    extended_headers: bool,
+    // the context ID of each context is its index
+    meta_contexts: Vec<MetaContext>,
+    // the export the metadata contexts were selected for
+    meta_context_export: String,
+}
+
+impl Session {
+    /// Forget the metadata contexts if they were selected for a different
+    /// export than the one the client is using.
+    fn use_export(&mut self, name: &str) {
+        if self.meta_context_export != name {
+            self.meta_contexts.clear();
+        }
+    }
+
+    /// Reply to a request that has no reply data (or to a read that failed).
+    fn reply<IO: Write>(&self, req: &Request, err: ErrorType, stream: &mut IO) -> Result<()> {
+        // simple replies are not allowed with extended headers, nor for reads
+        // with structured replies
+        if self.extended_headers || (self.structured_replies && req.typ == Cmd::READ) {
+            let reply = if err == ErrorType::OK {
+                StructuredReply::none(req)
+            } else {
+                StructuredReply::error(req, err, "")
+            };
+            reply.done().put(stream, self.extended_headers)?;
+        } else if err == ErrorType::OK {
+            SimpleReply::ok(req).put(stream)?;
+        } else {
+            SimpleReply::err(err, req).put(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Send the chunks of a structured reply, marking the last one as done.
+    fn put_chunks<IO: Write>(
+        &self,
+        req: &Request,
+        mut chunks: Vec<StructuredReply>,
+        stream: &mut IO,
+    ) -> Result<()> {
+        if chunks.is_empty() {
+            chunks.push(StructuredReply::none(req));
+        }
+        let last = chunks.len() - 1;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let chunk = if i == last { chunk.done() } else { chunk };
+            chunk.put(stream, self.extended_headers)?;
+        }
+        Ok(())
+    }
+}
+
+// Reads are split into blocks of this size when checking for holes and when
+// locating a read error.
+const READ_BLOCK_SIZE: usize = 4096;
+
+// Longest extent that can be reported without extended headers.
+const MAX_COMPACT_EXTENT: u64 = u32::MAX as u64 - (READ_BLOCK_SIZE as u64 - 1);
+
+/// A connection with its type erased, used after upgrading to TLS.
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// A socket that can be read on one thread while other threads write to it.
+trait Duplex: Read + Write + Send + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut down both directions, which wakes up a thread blocked reading.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Duplex for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Duplex for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Counts the requests that modify an export while workers carry them out,
+/// so that a flush can wait for the writes received before it.
+#[derive(Debug, Default)]
+struct PendingWrites {
+    count: Mutex<usize>,
+    done: Condvar,
+    // set when a worker fails, after which some writes may never finish
+    failed: AtomicBool,
+}
+
+impl PendingWrites {
+    fn start(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn finish(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    /// Stop waiting for writes, since the connection is ending.
+    fn fail(&self) {
+        let _count = self.count.lock().unwrap();
+        self.failed.store(true, Ordering::Relaxed);
+        self.done.notify_all();
+    }
+
+    fn wait(&self) {
+        let count = self.count.lock().unwrap();
+        let _count = self
+            .done
+            .wait_while(count, |count| {
+                *count > 0 && !self.failed.load(Ordering::Relaxed)
+            })
+            .unwrap();
+    }
+}
+
+/// Outcome of negotiation with a client.
+enum Negotiation {
+    /// Start the transmission phase for an export.
+    Transmit(Arc<Export>),
+    /// Upgrade the connection to TLS and then continue negotiating.
+    StartTls,
+    /// The client ended the connection.
+    Abort,
+}
+
+/// Resolve the empty export name to the default export.
+fn export_name(name: &str) -> &str {
+    if name.is_empty() {
+        DEFAULT_EXPORT
+    } else {
+        name
+    }
+}
+
+/// The handshake the server uses with clients.
+///
+/// Only [`Handshake::FixedNewstyle`] supports options such as export names,
+/// TLS, and structured replies; the others exist for compatibility with old
+/// clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Handshake {
+    /// Fixed newstyle negotiation, which is required by current clients.
+    #[default]
+    FixedNewstyle,
+    /// Also accept clients that do not support fixed newstyle negotiation.
+    /// These clients can only list exports and select one by name.
+    Newstyle,
+    /// Oldstyle negotiation, where the server immediately sends the size and
+    /// flags of [`DEFAULT_EXPORT`] and starts transmission.
+    Oldstyle,
+}
+
+#[derive(Debug)]
+struct ServerInner {
+    // exports, by name
+    exports: RwLock<BTreeMap<String, Arc<Export>>>,
+    tls: Option<ServerTls>,
+    handshake: RwLock<Handshake>,
+    // requests handled at once on each socket connection
+    workers: RwLock<usize>,
+}
+
+impl ServerInner {
+    /// Find an export for a client, returning the error to reply with if the
+    /// client cannot use it.
+    fn lookup(
+        &self,
+        name: &str,
+        session: &Session,
+    ) -> core::result::Result<Arc<Export>, ReplyType> {
+        let exports = self.exports.read().unwrap();
+        let export = exports
+            .get(export_name(name))
+            .ok_or(ReplyType::ERR_UNKNOWN)?;
+        if export.opts.tls == TlsPolicy::Required && !session.tls {
+            return Err(ReplyType::ERR_TLS_REQD);
+        }
+        Ok(export.clone())
+    }
+
+    /// Whether every export requires TLS, in which case clients cannot do
+    /// anything before upgrading the connection.
+    fn tls_forced(&self) -> bool {
+        let exports = self.exports.read().unwrap();
+        !exports.is_empty()
+            && exports
+                .values()
+                .all(|export| export.opts.tls == TlsPolicy::Required)
+    }
+
+    /// The server's supported operations, given the negotiated options.
+    fn transmit_flags(export: &Export, session: &Session) -> TransmitFlags {
+        let mut flags = TransmitFlags::HAS_FLAGS
+            | TransmitFlags::SEND_FLUSH
+            | TransmitFlags::SEND_FUA
+            | TransmitFlags::SEND_CACHE;
+        if session.structured_replies {
+            flags |= TransmitFlags::SEND_DF;
+        }
+        // without writes, every connection trivially sees the same data
+        if export.opts.read_only || export.backend.can_multi_conn() {
+            flags |= TransmitFlags::CAN_MULTI_CONN;
+        }
+        if export.opts.read_only {
+            flags |= TransmitFlags::READ_ONLY;
+        } else {
+            flags |= TransmitFlags::SEND_TRIM
+                | TransmitFlags::SEND_WRITE_ZEROES
+                | TransmitFlags::SEND_FAST_ZERO;
+            if export.backend.can_resize() {
+                flags |= TransmitFlags::SEND_RESIZE;
+            }
+        }
+        flags
+    }
+
+    /// Greet a client with oldstyle negotiation, returning the export to
+    /// transmit.
+    async fn oldstyle_handshake<C: Connection>(
+        &self,
+        conn: &mut C,
+        session: &mut Session,
+    ) -> Result<Arc<Export>> {
+        let export = self.lookup(DEFAULT_EXPORT, session).map_err(|err| {
+            ProtocolError::new(format!("cannot export {DEFAULT_EXPORT:?}: {err:?}"))
+        })?;
+        session.use_export(DEFAULT_EXPORT);
+        // S: 64 bits, 0x00420281861253 (cliserv_magic)
+        // S: 64 bits, size of the export in bytes (unsigned)
+        // S: 32 bits, flags (handshake flags are zero)
+        // S: 124 bytes, zeroes (reserved)
+        let mut out = vec![];
+        out.write_u64::<BE>(MAGIC)?;
+        out.write_u64::<BE>(CLISERV_MAGIC)?;
+        out.write_u64::<BE>(export.size().await?)?;
+        let transmit = Self::transmit_flags(&export, session);
+        out.write_u32::<BE>(transmit.bits() as u32)?;
+        out.write_all(&[0u8; 124])?;
+        conn.write_all(&out).await?;
+        Ok(export)
+    }
+
+    // Agree on basic negotiation flags.
+    async fn initial_handshake<C: Connection>(
+        conn: &mut C,
+        handshake: Handshake,
+    ) -> Result<HandshakeFlags> {
+        let mut out = vec![];
+        out.write_u64::<BE>(MAGIC)?;
+        out.write_u64::<BE>(IHAVEOPT)?;
+        out.write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
+        conn.write_all(&out).await?;
+        let mut client_flags = [0u8; 4];
+        conn.read_exact(&mut client_flags).await?;
+        let client_flags = u32::from_be_bytes(client_flags);
+        let client_flags = ClientHandshakeFlags::from_bits(client_flags)
+            .ok_or_else(|| ProtocolError::new(format!("unexpected client flags {client_flags}")))?;
+        let mut flags = HandshakeFlags::empty();
+        if client_flags.contains(ClientHandshakeFlags::C_FIXED_NEWSTYLE) {
+            flags |= HandshakeFlags::FIXED_NEWSTYLE;
+        } else if handshake != Handshake::Newstyle {
+            bail!(ProtocolError::new("client does not support FIXED_NEWSTYLE"));
+        }
+        if client_flags.contains(ClientHandshakeFlags::C_NO_ZEROES) {
+            flags |= HandshakeFlags::NO_ZEROES;
+        }
+        Ok(flags)
+    }
+
+    fn send_export_list<IO: Write>(&self, session: &Session, stream: &mut IO) -> Result<()> {
+        let exports = self
+            .exports
+            .read()
+            .unwrap()
+            .values()
+            // clients could not use these exports anyway
+            .filter(|export| session.tls || export.opts.tls == TlsPolicy::Optional)
+            .map(|export| (export.name.clone(), export.opts.description.clone()))
+            .collect();
+        ExportList::new(exports).put(stream)?;
+        Ok(())
+    }
+
+    /// Send export info at the end of newstyle negotiation, when client sends NBD_OPT_EXPORT_NAME.
+    async fn send_export_info<IO: Write>(
+        export: &Export,
+        stream: &mut IO,
+        flags: HandshakeFlags,
+        session: &Session,
+    ) -> Result<()> {
+        // If the value of the option field is `NBD_OPT_EXPORT_NAME` and the
+        // server is willing to allow the export, the server replies with
+        // information about the used export:
+        //
+        // S: 64 bits, size of the export in bytes (unsigned)
+        // S: 16 bits, transmission flags
+        // S: 124 bytes, zeroes (reserved) (unless `NBD_FLAG_C_NO_ZEROES` was negotiated by the client)
+        stream.write_u64::<BE>(export.size().await?)?;
+        let transmit = Self::transmit_flags(export, session);
+        stream.write_u16::<BE>(transmit.bits())?;
+        if !flags.contains(HandshakeFlags::NO_ZEROES) {
+            stream.write_all(&[0u8; 124])?;
+        }
+        Ok(())
+    }
+
+    async fn info_responses<IO: Write>(
+        export: &Export,
+        opt_typ: OptType,
+        info_req: InfoRequest,
+        session: &Session,
+        stream: &mut IO,
+    ) -> Result<()> {
+        for typ in info_req.typs.iter().chain([InfoType::EXPORT].iter()) {
+            match typ {
+                InfoType::EXPORT => {
+                    // Mandatory information before a successful completion of
+                    // NBD_OPT_INFO or NBD_OPT_GO. Describes the same
+                    // information that is sent in response to the older
+                    // NBD_OPT_EXPORT_NAME, except that there are no trailing
+                    // zeroes whether or not NBD_FLAG_C_NO_ZEROES was
+                    // negotiated. length MUST be 12, and the reply payload is
+                    // interpreted as follows:
+                    //
+                    // - 16 bits, NBD_INFO_EXPORT
+                    // - 64 bits, size of the export in bytes (unsigned)
+                    // - 16 bits, transmission flags
+                    let mut buf = vec![];
+                    buf.write_u16::<BE>(InfoType::EXPORT.into())?;
+                    buf.write_u64::<BE>(export.size().await?)?;
+                    buf.write_u16::<BE>(Self::transmit_flags(export, session).bits())?;
+                    OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
+                }
+                InfoType::BLOCK_SIZE => {
+                    // Represents the server's advertised block size
+                    // constraints; see the "Block size constraints" section for
+                    // more details on what these values represent, and on
+                    // constraints on their values. The server MUST send this
+                    // info if it is requested and it intends to enforce block
+                    // size constraints other than the defaults. After sending
+                    // this information in response to an NBD_OPT_GO in which
+                    // the client specifically requested NBD_INFO_BLOCK_SIZE,
+                    // the server can legitimately assume that any client that
+                    // continues the session will support the block size
+                    // constraints supplied (note that this assumption cannot be
+                    // made solely on the basis of an NBD_OPT_INFO with an
+                    // NBD_INFO_BLOCK_SIZE request, or an NBD_OPT_GO without an
+                    // explicit NBD_INFO_BLOCK_SIZE request). The length MUST be
                     // 14, and the reply payload is interpreted as:
                     //
                     //  -  16 bits, NBD_INFO_BLOCK_SIZE
@@ -336,16 +1727,41 @@ impl<F: Blocks> ServerInner<F> {
                     //  -  32 bits, preferred block size
                     //  -  32 bits, maximum block size
 
+                    let sizes = export.opts.block_sizes;
                     let mut buf = vec![];
                     buf.write_u16::<BE>(InfoType::BLOCK_SIZE.into())?;
-                    buf.write_u32::<BE>(1)?; // minimum
-                    buf.write_u32::<BE>(4096)?; // preferred
-                    buf.write_u32::<BE>(4096 * 32)?; // maximum
+                    buf.write_u32::<BE>(sizes.minimum)?;
+                    buf.write_u32::<BE>(sizes.preferred)?;
+                    buf.write_u32::<BE>(sizes.maximum)?;
                     OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
                 }
-                InfoType::NAME | InfoType::DESCRIPTION => {
-                    OptReply::new(opt_typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
-                    return Ok(());
+                InfoType::NAME => {
+                    // Represents the server's canonical name of the export.
+                    // The name MAY differ from the name presented in the
+                    // client's option request. The length MUST be at least 2,
+                    // and the reply payload is interpreted as:
+                    //
+                    // - 16 bits, NBD_INFO_NAME
+                    // - String: name of the export
+                    let mut buf = vec![];
+                    buf.write_u16::<BE>(InfoType::NAME.into())?;
+                    buf.write_all(export.name.as_bytes())?;
+                    OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
+                }
+                InfoType::DESCRIPTION => {
+                    // A description of the export, suitable for a human to
+                    // read. The server MAY omit this reply if it has no
+                    // description to send.
+                    //
+                    // - 16 bits, NBD_INFO_DESCRIPTION
+                    // - String: human-readable description of the export
+                    let Some(description) = &export.opts.description else {
+                        continue;
+                    };
+                    let mut buf = vec![];
+                    buf.write_u16::<BE>(InfoType::DESCRIPTION.into())?;
+                    buf.write_all(description.as_bytes())?;
+                    OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
                 }
             }
         }
@@ -353,135 +1769,929 @@ impl<F: Blocks> ServerInner<F> {
         Ok(())
     }
 
-    /// After the initial handshake, "haggle" to agree on connection parameters.
-    //
-    /// If this returns Ok(None), then the client wants to disconnect
-    fn handshake_haggle<IO: Read + Write>(
+    fn meta_context_responses<IO: Write>(
         &self,
+        opt_typ: OptType,
+        req: MetaContextRequest,
+        session: &mut Session,
         stream: &mut IO,
+    ) -> Result<()> {
+        let list = opt_typ == OptType::LIST_META_CONTEXT;
+        // metadata contexts can only be used with structured replies
+        if !list && !session.structured_replies {
+            OptReply::new(opt_typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+            return Ok(());
+        }
+        let export = match self.lookup(&req.name, session) {
+            Ok(export) => export,
+            Err(err) => {
+                OptReply::new(opt_typ, err, vec![]).put(stream)?;
+                return Ok(());
+            }
+        };
+        let mut contexts: Vec<MetaContext> = vec![];
+        if list && req.queries.is_empty() {
+            contexts = export.meta_contexts();
+        }
+        for query in &req.queries {
+            for context in export.match_meta_contexts(query, list) {
+                if !contexts.contains(&context) {
+                    contexts.push(context);
+                }
+            }
+        }
+        for (id, context) in contexts.iter().enumerate() {
+            // S: 32 bits, NBD metadata context ID.
+            // S: String, name of the metadata context.
+            //
+            // The context ID is only meaningful for SET, and is 0 for LIST.
+            let mut buf = vec![];
+            buf.write_u32::<BE>(if list { 0 } else { id as u32 })?;
+            buf.write_all(context.name().as_bytes())?;
+            OptReply::new(opt_typ, ReplyType::META_CONTEXT, buf).put(stream)?;
+        }
+        if !list {
+            session.meta_contexts = contexts;
+            session.meta_context_export = export_name(&req.name).to_string();
+        }
+        OptReply::ack(opt_typ).put(stream)?;
+        Ok(())
+    }
+
+    /// After the initial handshake, "haggle" to agree on connection parameters.
+    async fn handshake_haggle<C: Connection>(
+        &self,
+        conn: &mut C,
         flags: HandshakeFlags,
-    ) -> Result<Option<&Export<F>>> {
+        session: &mut Session,
+    ) -> Result<Negotiation> {
+        let mut out = vec![];
         loop {
-            let opt = Opt::get(stream)?;
-            match opt.typ {
-                OptType::EXPORT_NAME => {
-                    let _export: String = String::from_utf8(opt.data)
-                        .wrap_err(ProtocolError::new("non-UTF8 export name"))?;
-                    // requested export name is currently ignored since there is
-                    // only a single export
-                    self.send_export_info(stream, flags)?;
-                    return Ok(Some(&self.export));
+            let opt = conn.get_opt().await?;
+            out.clear();
+            let negotiation = self.handle_opt(opt, flags, session, &mut out).await;
+            // send the replies even if the option ends the connection
+            conn.write_all(&out).await?;
+            if let Some(negotiation) = negotiation? {
+                return Ok(negotiation);
+            }
+        }
+    }
+
+    /// Reply to a single option, returning the outcome if it ends
+    /// negotiation.
+    async fn handle_opt<IO: Write>(
+        &self,
+        opt: Opt,
+        flags: HandshakeFlags,
+        session: &mut Session,
+        stream: &mut IO,
+    ) -> Result<Option<Negotiation>> {
+        if !flags.contains(HandshakeFlags::FIXED_NEWSTYLE)
+            && !matches!(
+                opt.typ,
+                OptType::EXPORT_NAME | OptType::LIST | OptType::ABORT
+            )
+        {
+            // the client may not understand error replies, so the only
+            // safe way to refuse is closing the connection
+            bail!(ProtocolError::new(format!(
+                "client without FIXED_NEWSTYLE sent {:?}",
+                opt.typ
+            )));
+        }
+        if !session.tls
+            && self.tls_forced()
+            && !matches!(opt.typ, OptType::STARTTLS | OptType::ABORT)
+        {
+            // Every export requires TLS, so only allow upgrading the
+            // connection. NBD_OPT_EXPORT_NAME cannot be refused, so the
+            // connection is closed instead.
+            if opt.typ == OptType::EXPORT_NAME {
+                bail!(ProtocolError::new("client requested export without TLS"));
+            }
+            OptReply::new(opt.typ, ReplyType::ERR_TLS_REQD, vec![]).put(stream)?;
+            return Ok(None);
+        }
+        match opt.typ {
+            OptType::EXPORT_NAME => {
+                let name: String = String::from_utf8(opt.data)
+                    .wrap_err(ProtocolError::new("non-UTF8 export name"))?;
+                // there is no way to refuse NBD_OPT_EXPORT_NAME other than
+                // closing the connection
+                let export = self.lookup(&name, session).map_err(|err| {
+                    ProtocolError::new(format!("cannot export {name:?}: {err:?}"))
+                })?;
+                session.use_export(export_name(&name));
+                Self::send_export_info(&export, stream, flags, session).await?;
+                return Ok(Some(Negotiation::Transmit(export)));
+            }
+            OptType::LIST => {
+                if !opt.data.is_empty() {
+                    OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+                    return Ok(None);
                 }
-                OptType::LIST => {
-                    self.send_export_list(stream)?;
+                self.send_export_list(session, stream)?;
+            }
+            // the only difference between INFO and GO is that on success,
+            // GO starts the transmission phase
+            OptType::INFO | OptType::GO => {
+                let info_req = InfoRequest::get(&mut &opt.data[..])?;
+                let export = match self.lookup(&info_req.name, session) {
+                    Ok(export) => export,
+                    Err(err) => {
+                        OptReply::new(opt.typ, err, vec![]).put(stream)?;
+                        return Ok(None);
+                    }
+                };
+                if opt.typ == OptType::GO
+                    && export.opts.block_sizes.minimum > 1
+                    && !info_req.typs.contains(&InfoType::BLOCK_SIZE)
+                {
+                    // the client would not know to align its requests
+                    OptReply::new(opt.typ, ReplyType::ERR_BLOCK_SIZE_REQD, vec![]).put(stream)?;
+                    return Ok(None);
                 }
-                // the only difference between INFO and GO is that on success,
-                // GO starts the transmission phase
-                OptType::INFO => {
-                    let info_req = InfoRequest::get(&mut &opt.data[..])?;
-                    self.info_responses(opt.typ, info_req, stream)?;
+                let name = export_name(&info_req.name).to_string();
+                Self::info_responses(&export, opt.typ, info_req, session, stream).await?;
+                if opt.typ == OptType::GO {
+                    session.use_export(&name);
+                    return Ok(Some(Negotiation::Transmit(export)));
                 }
-                OptType::GO => {
-                    let info_req = InfoRequest::get(&mut &opt.data[..])?;
-                    self.info_responses(opt.typ, info_req, stream)?;
-                    return Ok(Some(&self.export));
+            }
+            OptType::ABORT => {
+                return Ok(Some(Negotiation::Abort));
+            }
+            OptType::STARTTLS => {
+                if self.tls.is_none() {
+                    OptReply::new(opt.typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
+                    return Ok(None);
                 }
-                OptType::ABORT => {
+                if session.tls || !opt.data.is_empty() {
+                    OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
                     return Ok(None);
                 }
-                _ => {
-                    warn!("got unsupported option {:?}", opt);
-                    OptReply::new(opt.typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
+                OptReply::ack(opt.typ).put(stream)?;
+                return Ok(Some(Negotiation::StartTls));
+            }
+            OptType::LIST_META_CONTEXT | OptType::SET_META_CONTEXT => {
+                let req = MetaContextRequest::get(&mut &opt.data[..])?;
+                self.meta_context_responses(opt.typ, req, session, stream)?;
+            }
+            OptType::STRUCTURED_REPLY | OptType::EXTENDED_HEADERS => {
+                if !opt.data.is_empty() {
+                    OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+                    return Ok(None);
+                }
+                session.structured_replies = true;
+                if opt.typ == OptType::EXTENDED_HEADERS {
+                    session.extended_headers = true;
+                }
+                OptReply::ack(opt.typ).put(stream)?;
+            }
+            _ => {
+                warn!("got unsupported option {:?}", opt);
+                OptReply::new(opt.typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reply to a read using a structured reply.
+    ///
+    /// Blocks of zeroes are sent as holes (unless the client asked not to
+    /// fragment the reply), and if the read fails, the data before the
+    /// failing block is still sent, followed by an error with its offset.
+    async fn structured_read<IO: Write>(
+        export: &Export,
+        session: &Session,
+        req: &Request,
+        buf: &mut [u8],
+        stream: &mut IO,
+    ) -> Result<()> {
+        if buf.len() < req.len as usize {
+            StructuredReply::error(req, ErrorType::EOVERFLOW, "read is too large")
+                .done()
+                .put(stream, session.extended_headers)?;
+            return Ok(());
+        }
+        let len = req.len as usize;
+        let buf = &mut buf[..len];
+        let mut failed = None;
+        if export.backend.read_at(buf, req.offset).await.is_err() {
+            // re-read block-by-block to find where the error is
+            for (i, block) in buf.chunks_mut(READ_BLOCK_SIZE).enumerate() {
+                let block_off = i * READ_BLOCK_SIZE;
+                if let Err(err) = export
+                    .backend
+                    .read_at(block, req.offset + block_off as u64)
+                    .await
+                {
+                    failed = Some((block_off, ErrorType::from_io_kind(err.kind())));
+                    break;
+                }
+            }
+        }
+        let data = &buf[..failed.map(|(off, _)| off).unwrap_or(len)];
+
+        let mut chunks = vec![];
+        if req.flags.contains(CmdFlags::DF) {
+            if !data.is_empty() {
+                chunks.push(StructuredReply::offset_data(req, req.offset, data));
+            }
+        } else {
+            let is_zero = |start: usize| {
+                let end = (start + READ_BLOCK_SIZE).min(data.len());
+                data[start..end].iter().all(|&b| b == 0)
+            };
+            let mut start = 0;
+            while start < data.len() {
+                let zero = is_zero(start);
+                let mut end = start;
+                while end < data.len() && is_zero(end) == zero {
+                    end = (end + READ_BLOCK_SIZE).min(data.len());
+                }
+                let offset = req.offset + start as u64;
+                chunks.push(if zero {
+                    StructuredReply::offset_hole(req, offset, (end - start) as u32)
+                } else {
+                    StructuredReply::offset_data(req, offset, &data[start..end])
+                });
+                start = end;
+            }
+        }
+        if let Some((off, err)) = failed {
+            warn!(target: "nbd", "read error {:?} at offset {}", err, req.offset + off as u64);
+            chunks.push(StructuredReply::error_offset(
+                req,
+                err,
+                req.offset + off as u64,
+                "read failed",
+            ));
+        }
+        session.put_chunks(req, chunks, stream)
+    }
+
+    /// Reply to a block status request with the status of the range in each
+    /// selected metadata context.
+    async fn block_status<IO: Write>(
+        export: &Export,
+        session: &Session,
+        req: &Request,
+        stream: &mut IO,
+    ) -> Result<()> {
+        if session.meta_contexts.is_empty() {
+            warn!(target: "nbd", "block status without any metadata contexts");
+            return session.reply(req, ErrorType::EINVAL, stream);
+        }
+        let req_one = req.flags.contains(CmdFlags::REQ_ONE);
+        let mut chunks = vec![];
+        for (id, context) in session.meta_contexts.iter().enumerate() {
+            let extents = export
+                .extents(
+                    context,
+                    req.offset,
+                    req.len,
+                    req_one,
+                    session.extended_headers,
+                )
+                .await;
+            match extents {
+                Ok(extents) => chunks.push(StructuredReply::block_status(
+                    req,
+                    id as u32,
+                    &extents,
+                    session.extended_headers,
+                )),
+                Err(err) => {
+                    warn!(target: "nbd", "block status error {:?}", err);
+                    return session.reply(req, err, stream);
+                }
+            }
+        }
+        session.put_chunks(req, chunks, stream)
+    }
+
+    /// Check a request against the export's block size constraints.
+    fn check_block_sizes(export: &Export, req: &Request) -> core::result::Result<(), ErrorType> {
+        let sizes = export.opts.block_sizes;
+        match req.typ {
+            Cmd::READ | Cmd::WRITE => {
+                if req.len > sizes.maximum as u64 {
+                    return Err(ErrorType::EINVAL);
                 }
             }
+            Cmd::TRIM | Cmd::WRITE_ZEROES | Cmd::CACHE | Cmd::BLOCK_STATUS => {}
+            _ => return Ok(()),
         }
+        if !sizes.aligned(req.offset, req.len) {
+            return Err(ErrorType::EINVAL);
+        }
+        Ok(())
     }
 
-    fn handle_ops<IO: Read + Write>(export: &Export<F>, stream: &mut IO) -> Result<()> {
-        let mut buf = vec![0u8; 4096 * 64];
+    async fn handle_ops<C: Connection>(
+        export: &Export,
+        session: &Session,
+        conn: &mut C,
+    ) -> Result<()> {
+        // large enough for any read or write the export allows (the memory is
+        // only committed when used)
+        let mut buf = vec![0u8; export.opts.block_sizes.maximum as usize];
+        let mut out = vec![];
         loop {
-            assert_eq!(buf.len(), 4096 * 64);
-            let req = Request::get(stream, &mut buf)?;
+            let req = conn.get_request(&mut buf, session.extended_headers).await?;
             info!(target: "nbd", "{:?}", req);
-            // only FUA is supported
-            if req.flags.intersects(CmdFlags::FUA.complement()) {
-                warn!(target: "nbd", "unexpected flags {:?}", req.flags);
-                SimpleReply::err(ErrorType::ENOTSUP, &req).put(stream)?;
-                continue;
+            out.clear();
+            let more = Self::handle_request(export, session, &req, &mut buf, &mut out).await?;
+            conn.write_all(&out).await?;
+            if !more {
+                return Ok(());
             }
-            match req.typ {
-                Cmd::READ => match export.read(req.offset, req.len, &mut buf) {
-                    Ok(data) => SimpleReply::data(&req, data).put(stream)?,
-                    Err(err) => {
-                        warn!(target: "nbd", "read error {:?}", err);
-                        SimpleReply::err(err, &req).put(stream)?;
+        }
+    }
+
+    /// Carry out a single request and write its reply, returning false if
+    /// the client disconnected.
+    async fn handle_request<IO: Write>(
+        export: &Export,
+        session: &Session,
+        req: &Request,
+        buf: &mut [u8],
+        stream: &mut IO,
+    ) -> Result<bool> {
+        let mut supported_flags = CmdFlags::FUA | CmdFlags::NO_HOLE | CmdFlags::FAST_ZERO;
+        if session.structured_replies {
+            supported_flags |= CmdFlags::DF | CmdFlags::REQ_ONE;
+        }
+        if req.flags.intersects(supported_flags.complement()) {
+            warn!(target: "nbd", "unexpected flags {:?}", req.flags);
+            session.reply(req, ErrorType::ENOTSUP, stream)?;
+            return Ok(true);
+        }
+        if export.opts.read_only
+            && matches!(
+                req.typ,
+                Cmd::WRITE | Cmd::TRIM | Cmd::WRITE_ZEROES | Cmd::RESIZE
+            )
+        {
+            warn!(target: "nbd", "{:?} on read-only export", req.typ);
+            session.reply(req, ErrorType::EPERM, stream)?;
+            return Ok(true);
+        }
+        if let Err(err) = Self::check_block_sizes(export, req) {
+            warn!(target: "nbd", "request violates block sizes {:?}", req);
+            session.reply(req, err, stream)?;
+            return Ok(true);
+        }
+        match req.typ {
+            Cmd::READ if session.structured_replies => {
+                Self::structured_read(export, session, req, buf, stream).await?;
+            }
+            Cmd::READ => match export.read(req.offset, req.len, buf).await {
+                Ok(data) => SimpleReply::data(req, data).put(stream)?,
+                Err(err) => {
+                    warn!(target: "nbd", "read error {:?}", err);
+                    SimpleReply::err(err, req).put(stream)?;
+                }
+            },
+            Cmd::WRITE => match export.write(req.offset, req.data_len, buf).await {
+                Ok(_) => {
+                    if req.flags.contains(CmdFlags::FUA) {
+                        export.flush().await?;
                     }
-                },
-                Cmd::WRITE => match export.write(req.offset, req.data_len, &buf) {
+                    session.reply(req, ErrorType::OK, stream)?;
+                }
+                Err(err) => {
+                    warn!(target: "nbd", "write error {:?}", err);
+                    session.reply(req, err, stream)?;
+                }
+            },
+            Cmd::WRITE_ZEROES => {
+                let punch_hole = !req.flags.contains(CmdFlags::NO_HOLE);
+                let fast = req.flags.contains(CmdFlags::FAST_ZERO);
+                match export
+                    .write_zeroes(req.offset, req.len, punch_hole, fast)
+                    .await
+                {
                     Ok(_) => {
                         if req.flags.contains(CmdFlags::FUA) {
-                            export.flush()?;
+                            export.flush().await?;
                         }
-                        SimpleReply::ok(&req).put(stream)?;
+                        session.reply(req, ErrorType::OK, stream)?;
+                    }
+                    // an expected failure when the client asks for fast
+                    // zeroing
+                    Err(ErrorType::ENOTSUP) if fast => {
+                        session.reply(req, ErrorType::ENOTSUP, stream)?;
                     }
                     Err(err) => {
-                        warn!(target: "nbd", "write error {:?}", err);
-                        SimpleReply::err(err, &req).put(stream)?;
+                        warn!(target: "nbd", "write zeroes error {:?}", err);
+                        session.reply(req, err, stream)?;
                     }
-                },
-                Cmd::DISCONNECT => {
-                    // don't send a reply - RFC says server can send an ACK, but
-                    // Linux client closes the connection immediately
-                    return Ok(());
                 }
-                Cmd::FLUSH => {
-                    export.flush()?;
-                    SimpleReply::ok(&req).put(stream)?;
+            }
+            Cmd::DISCONNECT => {
+                // don't send a reply - RFC says server can send an ACK, but
+                // Linux client closes the connection immediately
+                return Ok(false);
+            }
+            Cmd::FLUSH => {
+                export.flush().await?;
+                session.reply(req, ErrorType::OK, stream)?;
+            }
+            Cmd::TRIM => match export.trim(req.offset, req.len).await {
+                Ok(_) => {
+                    if req.flags.contains(CmdFlags::FUA) {
+                        export.flush().await?;
+                    }
+                    session.reply(req, ErrorType::OK, stream)?;
+                }
+                Err(err) => {
+                    warn!(target: "nbd", "trim error {:?}", err);
+                    session.reply(req, err, stream)?;
+                }
+            },
+            Cmd::BLOCK_STATUS => {
+                Self::block_status(export, session, req, stream).await?;
+            }
+            // the new size is in the length, and the offset is unused
+            Cmd::RESIZE if req.offset != 0 => {
+                session.reply(req, ErrorType::EINVAL, stream)?;
+            }
+            Cmd::RESIZE => match export.resize(req.len).await {
+                Ok(_) => session.reply(req, ErrorType::OK, stream)?,
+                Err(err) => {
+                    warn!(target: "nbd", "resize error {:?}", err);
+                    session.reply(req, err, stream)?;
                 }
-                Cmd::TRIM => {
-                    SimpleReply::ok(&req).put(stream)?;
+            },
+            Cmd::CACHE => match export.prefetch(req.offset, req.len).await {
+                Ok(_) => session.reply(req, ErrorType::OK, stream)?,
+                Err(err) => {
+                    warn!(target: "nbd", "cache error {:?}", err);
+                    session.reply(req, err, stream)?;
                 }
-                _ => {
-                    SimpleReply::err(ErrorType::ENOTSUP, &req).put(stream)?;
+            },
+        }
+        Ok(true)
+    }
+
+    /// Run the transmission phase with a client, and return on disconnect.
+    async fn transmit<C: Connection>(
+        export: &Export,
+        session: &Session,
+        conn: &mut C,
+    ) -> Result<()> {
+        let r = Self::handle_ops(export, session, conn)
+            .await
+            .wrap_err("handling client operations");
+        Self::allow_eof(r)
+    }
+
+    /// Treat the client closing the connection as a graceful disconnect.
+    fn allow_eof(r: Result<()>) -> Result<()> {
+        if let Err(err) = r {
+            // if the error is due to UnexpectedEof, then the client closed
+            // the connection, which the server should allow gracefully
+            if let Some(err) = err.root_cause().downcast_ref::<io::Error>() {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
                     return Ok(());
                 }
             }
+            return Err(err);
         }
+        Ok(())
     }
 
-    /// Handle a single client, and return on disconnect.
-    fn handle_client<IO: Read + Write>(&self, mut stream: IO) -> Result<()> {
-        let flags = Self::initial_handshake(&mut stream).wrap_err("initial handshake failed")?;
-        if let Some(export) = self
-            .handshake_haggle(&mut stream, flags)
-            .wrap_err("handshake haggling failed")?
-        {
-            info!("handshake finished with {:?}", flags);
-            let r = Self::handle_ops(export, &mut stream).wrap_err("handling client operations");
-            if let Err(err) = r {
-                // if the error is due to UnexpectedEof, then the client closed
-                // the connection, which the server should allow gracefully
-                if let Some(err) = err.root_cause().downcast_ref::<io::Error>() {
-                    if err.kind() == io::ErrorKind::UnexpectedEof {
-                        return Ok(());
+    /// Run the transmission phase with requests carried out by `workers`
+    /// threads, each of which replies as soon as its request completes.
+    ///
+    /// Flushes wait for the writes received before them to complete, so that
+    /// they cover every write the client sent first.
+    fn transmit_concurrent<S: Duplex>(
+        export: &Export,
+        session: &Session,
+        mut stream: S,
+        workers: usize,
+    ) -> Result<()> {
+        let writer = Mutex::new(stream.try_clone()?);
+        let writes = PendingWrites::default();
+        // the first error from a worker, which ends the connection
+        let failed = Mutex::new(None);
+        // bounded, so that a slow export also slows down reading requests
+        let (tx, rx) = mpsc::sync_channel::<(Request, Vec<u8>)>(workers);
+        // shared by the workers only, so that sending fails once they have
+        // all stopped
+        let rx = Arc::new(Mutex::new(rx));
+        let r = thread::scope(|scope| {
+            for _ in 0..workers {
+                let (rx, writer, writes, failed) = (Arc::clone(&rx), &writer, &writes, &failed);
+                scope.spawn(move || {
+                    // grown to the largest read so far
+                    let mut buf = vec![];
+                    let mut out = vec![];
+                    loop {
+                        // only hold the lock while waiting for a request
+                        let job = rx.lock().unwrap().recv();
+                        let Ok((req, mut data)) = job else {
+                            return;
+                        };
+                        let buf = match req.typ {
+                            // writes come with their own data
+                            Cmd::WRITE => &mut data,
+                            Cmd::READ => {
+                                let max = export.opts.block_sizes.maximum;
+                                let len = req.len.min(max.into()) as usize;
+                                if buf.len() < len {
+                                    buf.resize(len, 0);
+                                }
+                                &mut buf
+                            }
+                            _ => &mut buf,
+                        };
+                        out.clear();
+                        let r =
+                            block_on(Self::handle_request(export, session, &req, buf, &mut out))
+                                .and_then(|_| {
+                                    let mut writer = writer.lock().unwrap();
+                                    writer.write_all(&out)?;
+                                    writer.flush()?;
+                                    Ok(())
+                                });
+                        if Self::modifies(&req) {
+                            writes.finish();
+                        }
+                        if let Err(err) = r {
+                            failed.lock().unwrap().get_or_insert(err);
+                            // stop reading requests, and waiting for writes
+                            // that may never finish
+                            let _ = writer.lock().unwrap().shutdown();
+                            writes.fail();
+                            return;
+                        }
+                    }
+                });
+            }
+            drop(rx);
+            let r = loop {
+                let mut req = match Request::get_header(&mut stream, session.extended_headers) {
+                    Ok(req) => req,
+                    Err(err) => break Err(err),
+                };
+                // the data of a write, sized to the request
+                let mut data = vec![];
+                if req.typ == Cmd::WRITE {
+                    data = vec![0u8; req.len.min(export.opts.block_sizes.maximum.into()) as usize];
+                    if let Err(err) = req.get_data(&mut stream, &mut data) {
+                        break Err(err);
                     }
                 }
-                return Err(err);
+                info!(target: "nbd", "{:?}", req);
+                match req.typ {
+                    // the workers finish the requests received so far
+                    Cmd::DISCONNECT => break Ok(()),
+                    Cmd::FLUSH => writes.wait(),
+                    _ if Self::modifies(&req) => writes.start(),
+                    _ => {}
+                }
+                if tx.send((req, data)).is_err() {
+                    // every worker failed
+                    break Ok(());
+                }
+            };
+            drop(tx);
+            r
+        });
+        if let Some(err) = failed.into_inner().unwrap() {
+            return Err(err.wrap_err("handling client operations"));
+        }
+        Self::allow_eof(r.wrap_err("handling client operations"))
+    }
+
+    /// Whether a request modifies the export, so that flushes must wait for
+    /// it.
+    fn modifies(req: &Request) -> bool {
+        matches!(
+            req.typ,
+            Cmd::WRITE | Cmd::WRITE_ZEROES | Cmd::TRIM | Cmd::RESIZE
+        )
+    }
+
+    /// Negotiate with a new client, until it selects an export, disconnects,
+    /// or asks to upgrade to TLS.
+    async fn negotiate<C: Connection>(
+        &self,
+        conn: &mut C,
+        session: &mut Session,
+    ) -> Result<(HandshakeFlags, Negotiation)> {
+        let handshake = *self.handshake.read().unwrap();
+        if handshake == Handshake::Oldstyle {
+            let export = self
+                .oldstyle_handshake(conn, session)
+                .await
+                .wrap_err("oldstyle handshake failed")?;
+            return Ok((HandshakeFlags::empty(), Negotiation::Transmit(export)));
+        }
+        let flags = Self::initial_handshake(conn, handshake)
+            .await
+            .wrap_err("initial handshake failed")?;
+        let negotiation = self
+            .handshake_haggle(conn, flags, session)
+            .await
+            .wrap_err("handshake haggling failed")?;
+        Ok((flags, negotiation))
+    }
+
+    /// Negotiate again after upgrading to TLS. Options negotiated before TLS
+    /// do not carry over.
+    async fn negotiate_tls<C: Connection>(
+        &self,
+        conn: &mut C,
+        flags: HandshakeFlags,
+        session: &mut Session,
+    ) -> Result<Negotiation> {
+        *session = Session {
+            tls: true,
+            ..Default::default()
+        };
+        self.handshake_haggle(conn, flags, session)
+            .await
+            .wrap_err("handshake haggling over TLS failed")
+    }
+
+    /// Finish with a client once negotiation is over.
+    async fn finish<C: Connection>(
+        negotiation: Negotiation,
+        flags: HandshakeFlags,
+        session: &Session,
+        conn: &mut C,
+    ) -> Result<()> {
+        match negotiation {
+            Negotiation::Transmit(export) => {
+                info!("handshake finished with {:?} {:?}", flags, session);
+                Self::transmit(&export, session, conn).await
             }
+            Negotiation::Abort => Ok(()),
+            Negotiation::StartTls => bail!(ProtocolError::new("TLS negotiated twice")),
         }
-        Ok(())
+    }
+
+    /// Handle a single client, and return on disconnect.
+    fn handle_client<'a, IO: Read + Write + 'a>(&self, stream: IO) -> Result<()> {
+        let mut conn = Blocking(stream);
+        let mut session = Session::default();
+        let (flags, negotiation) = block_on(self.negotiate(&mut conn, &mut session))?;
+        let Negotiation::StartTls = negotiation else {
+            return block_on(Self::finish(negotiation, flags, &session, &mut conn));
+        };
+        self.handle_tls(conn.0, flags, session)
+    }
+
+    /// Continue with a client that asked to upgrade the connection to TLS.
+    fn handle_tls<'a, IO: Read + Write + 'a>(
+        &self,
+        stream: IO,
+        flags: HandshakeFlags,
+        mut session: Session,
+    ) -> Result<()> {
+        let tls = self.tls.as_ref().expect("STARTTLS without TLS configured");
+        let stream = tls.accept(stream)?;
+        // Continue with a trait object, so that negotiation is not
+        // instantiated for TLS over every stream type.
+        let mut conn = Blocking(Box::new(stream) as Box<dyn Stream + 'a>);
+        block_on(async {
+            let negotiation = self.negotiate_tls(&mut conn, flags, &mut session).await?;
+            Self::finish(negotiation, flags, &session, &mut conn).await
+        })
+    }
+
+    /// Handle a single client on a socket, with several requests at once if
+    /// the server has workers (see [`Server::set_workers`]).
+    fn handle_socket<S: Duplex + 'static>(&self, stream: S) -> Result<()> {
+        let workers = *self.workers.read().unwrap();
+        if workers == 1 {
+            return self.handle_client(stream);
+        }
+        let mut conn = Blocking(stream);
+        let mut session = Session::default();
+        let (flags, negotiation) = block_on(self.negotiate(&mut conn, &mut session))?;
+        match negotiation {
+            Negotiation::Transmit(export) => {
+                info!("handshake finished with {:?} {:?}", flags, session);
+                Self::transmit_concurrent(&export, &session, conn.0, workers)
+            }
+            // a TLS session cannot be read and written from different
+            // threads, so it handles one request at a time
+            Negotiation::StartTls => self.handle_tls(conn.0, flags, session),
+            Negotiation::Abort => Ok(()),
+        }
+    }
+
+    /// Handle a single client over an async stream, and return on
+    /// disconnect.
+    #[cfg(feature = "tokio")]
+    async fn handle_client_async<IO>(&self, stream: IO) -> Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut conn = Tokio(stream);
+        let mut session = Session::default();
+        let (flags, negotiation) = self.negotiate(&mut conn, &mut session).await?;
+        let Negotiation::StartTls = negotiation else {
+            return Self::finish(negotiation, flags, &session, &mut conn).await;
+        };
+        let tls = self.tls.as_ref().expect("STARTTLS without TLS configured");
+        let mut conn = Tokio(tls.accept_async(conn.0).await?);
+        let negotiation = self.negotiate_tls(&mut conn, flags, &mut session).await?;
+        Self::finish(negotiation, flags, &session, &mut conn).await
     }
 }
 
-/// Server implements the NBD protocol, with a single export.
-#[derive(Debug)]
-pub struct Server<F: Blocks>(Arc<ServerInner<F>>);
+/// Server implements the NBD protocol, with any number of named exports.
+///
+/// Cloning a Server gives another handle to the same server, for example to
+/// manage exports and checkpoints while it is running.
+#[derive(Debug, Clone)]
+pub struct Server(Arc<ServerInner>);
+
+impl Server {
+    /// Create a Server that exports blocks as [`DEFAULT_EXPORT`].
+    pub fn new<F: Blocks + Sync + Send + 'static>(blocks: F) -> Self {
+        let server = Self::empty(None);
+        server
+            .add_export(DEFAULT_EXPORT, blocks, ExportOptions::default())
+            .expect("default export is valid");
+        server
+    }
+
+    /// Create a Server that exports blocks as [`DEFAULT_EXPORT`] and supports
+    /// upgrading connections to TLS.
+    ///
+    /// If `policy` is [`TlsPolicy::Required`], clients must upgrade before
+    /// they can do anything else.
+    pub fn with_tls<F: Blocks + Sync + Send + 'static>(
+        blocks: F,
+        tls: ServerTls,
+        policy: TlsPolicy,
+    ) -> Self {
+        let server = Self::empty(Some(tls));
+        server
+            .add_export(
+                DEFAULT_EXPORT,
+                blocks,
+                ExportOptions {
+                    tls: policy,
+                    ..Default::default()
+                },
+            )
+            .expect("default export is valid");
+        server
+    }
+
+    /// Create a Server without any exports (see [`Server::add_export`]),
+    /// which supports upgrading connections to TLS if `tls` is given.
+    pub fn empty(tls: Option<ServerTls>) -> Self {
+        Self(Arc::new(ServerInner {
+            exports: RwLock::new(BTreeMap::new()),
+            tls,
+            handshake: RwLock::new(Handshake::default()),
+            workers: RwLock::new(1),
+        }))
+    }
+
+    /// Change the handshake used for new connections, for compatibility with
+    /// old clients.
+    pub fn set_handshake(&self, handshake: Handshake) {
+        *self.0.handshake.write().unwrap() = handshake;
+    }
+
+    /// Handle up to `workers` requests at once on each new connection, each
+    /// on its own thread, replying to requests as they complete (possibly
+    /// out of order).
+    ///
+    /// This only applies to connections the server accepts itself (see
+    /// [`Server::start_on`] and [`Server::start_unix`]) that do not use TLS.
+    /// By default, each connection handles one request at a time.
+    pub fn set_workers(&self, workers: usize) {
+        *self.0.workers.write().unwrap() = workers.max(1);
+    }
+
+    /// Export blocks under a name.
+    ///
+    /// Exports can be added while the server is running, and can use different
+    /// kinds of Blocks.
+    pub fn add_export<F: Blocks + Sync + Send + 'static>(
+        &self,
+        name: &str,
+        blocks: F,
+        opts: ExportOptions,
+    ) -> Result<()> {
+        self.insert_export(name, Backend::Blocking(Arc::new(blocks)), opts)
+    }
+
+    /// Export async Blocks under a name (see [`Server::add_export`]).
+    ///
+    /// This must be called within a tokio runtime, which runs the Blocks for
+    /// connections handled by blocking threads (with [`Server::handle_client`]
+    /// or [`Server::start`]). A current-thread runtime only does I/O and timers
+    /// for those connections while it is running [`Runtime::block_on`] on
+    /// another thread.
+    ///
+    /// [`Runtime::block_on`]: tokio::runtime::Runtime::block_on
+    #[cfg(feature = "tokio")]
+    pub fn add_async_export<F: AsyncBlocks + 'static>(
+        &self,
+        name: &str,
+        blocks: F,
+        opts: ExportOptions,
+    ) -> Result<()> {
+        let runtime = tokio::runtime::Handle::try_current().wrap_err_with(|| {
+            format!("async export {name} must be added within a tokio runtime")
+        })?;
+        self.insert_export(name, Backend::Async(Box::new(blocks), runtime), opts)
+    }
+
+    fn insert_export(&self, name: &str, backend: Backend, opts: ExportOptions) -> Result<()> {
+        // the empty name is an alias for the default export
+        ensure!(!name.is_empty(), "export name cannot be empty");
+        ensure!(name.len() <= 4096, "export name {name} is too long");
+        opts.block_sizes
+            .validate()
+            .wrap_err_with(|| format!("invalid block sizes for export {name}"))?;
+        ensure!(
+            opts.tls == TlsPolicy::Optional || self.0.tls.is_some(),
+            "export {name} requires TLS, but the server does not support TLS"
+        );
+        let mut exports = self.0.exports.write().unwrap();
+        ensure!(!exports.contains_key(name), "export {name} already exists");
+        exports.insert(name.to_string(), Arc::new(Export::new(name, backend, opts)));
+        Ok(())
+    }
+
+    /// Stop offering an export. Clients that are already using it are not
+    /// disconnected.
+    pub fn remove_export(&self, name: &str) -> Result<()> {
+        let mut exports = self.0.exports.write().unwrap();
+        exports
+            .remove(name)
+            .ok_or_else(|| eyre!("no export {name}"))?;
+        Ok(())
+    }
+
+    /// Get the names of all exports.
+    pub fn exports(&self) -> Vec<String> {
+        self.0.exports.read().unwrap().keys().cloned().collect()
+    }
+
+    fn export(&self, name: &str) -> Result<Arc<Export>> {
+        let exports = self.0.exports.read().unwrap();
+        let export = exports.get(name).ok_or_else(|| eyre!("no export {name}"))?;
+        Ok(export.clone())
+    }
+
+    /// Create a checkpoint on an export, which tracks the blocks written from
+    /// now on.
+    ///
+    /// Clients can query the written blocks with block status requests in the
+    /// `qemu:dirty-bitmap:<name>` metadata context (see
+    /// [`QEMU_DIRTY_BITMAP`]).
+    pub fn create_checkpoint(&self, export: &str, name: &str) -> Result<()> {
+        let export = self.export(export)?;
+        let mut checkpoints = export.checkpoints.lock().unwrap();
+        ensure!(
+            !checkpoints.contains_key(name),
+            "checkpoint {name} already exists"
+        );
+        checkpoints.insert(name.to_string(), DirtyBitmap::default());
+        Ok(())
+    }
+
+    /// Clear a checkpoint, so that it only tracks blocks written from now on
+    /// (for example, after a backup has copied the previously written blocks).
+    pub fn clear_checkpoint(&self, export: &str, name: &str) -> Result<()> {
+        let export = self.export(export)?;
+        let mut checkpoints = export.checkpoints.lock().unwrap();
+        let bitmap = checkpoints
+            .get_mut(name)
+            .ok_or_else(|| eyre!("no checkpoint {name}"))?;
+        *bitmap = DirtyBitmap::default();
+        Ok(())
+    }
+
+    /// Stop tracking writes for a checkpoint.
+    pub fn remove_checkpoint(&self, export: &str, name: &str) -> Result<()> {
+        let export = self.export(export)?;
+        let mut checkpoints = export.checkpoints.lock().unwrap();
+        checkpoints
+            .remove(name)
+            .ok_or_else(|| eyre!("no checkpoint {name}"))?;
+        Ok(())
+    }
 
-impl<F: Blocks + Sync + Send + 'static> Server<F> {
-    /// Create a Server that exports blocks.
-    pub fn new(blocks: F) -> Self {
-        let export = Export(blocks);
-        Self(Arc::new(ServerInner { export }))
+    /// Get the names of all checkpoints on an export.
+    pub fn checkpoints(&self, export: &str) -> Result<Vec<String>> {
+        Ok(self.export(export)?.checkpoint_names())
     }
 
     /// Handshake and communicate with a client on a single connection.
@@ -491,20 +2701,144 @@ impl<F: Blocks + Sync + Send + 'static> Server<F> {
         self.0.handle_client(stream)
     }
 
-    /// Start accepting connections from clients and processing commands.
+    /// Handshake and communicate with a client on a single async connection.
+    ///
+    /// Returns Ok(()) when client gracefully disconnects. Operations on
+    /// exports with blocking [`Blocks`] run on tokio's blocking thread pool
+    /// (see [`tokio::task::spawn_blocking`]); [`AsyncBlocks`] avoid a thread
+    /// per operation.
+    #[cfg(feature = "tokio")]
+    pub async fn handle_client_async<IO>(&self, stream: IO) -> Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.0.handle_client_async(stream).await
+    }
+
+    /// Start accepting connections from local clients on `port`, and
+    /// processing commands.
+    ///
+    /// See [`Server::start_on`] to accept connections from other hosts.
     pub fn start(self, port: u16) -> Result<()> {
-        let addr = ("127.0.0.1", port);
-        let listener = TcpListener::bind(addr)?;
+        self.start_on(&[SocketAddr::from(([127, 0, 0, 1], port))])
+    }
+
+    /// Start accepting connections from clients on every address in `addrs`,
+    /// and processing commands.
+    ///
+    /// IPv6 addresses also accept IPv4 connections (dual-stack), unless an
+    /// IPv4 address with the same port is listed too.
+    pub fn start_on(self, addrs: &[SocketAddr]) -> Result<()> {
+        ensure!(!addrs.is_empty(), "no addresses to listen on");
+        // bind everything first, so that a bad address fails right away
+        let listeners = addrs
+            .iter()
+            .map(|addr| {
+                let v6_only = addrs.iter().any(|a| a.is_ipv4() && a.port() == addr.port());
+                bind_tcp(*addr, v6_only).wrap_err_with(|| format!("binding {addr}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (tx, rx) = mpsc::channel();
+        for listener in listeners {
+            let server = self.clone();
+            let tx = tx.clone();
+            thread::spawn(move || tx.send(server.accept_tcp(listener)));
+        }
+        // listeners only stop on errors, so report the first one
+        rx.recv().expect("listener threads hung up")
+    }
+
+    fn accept_tcp(&self, listener: TcpListener) -> Result<()> {
         for stream in listener.incoming() {
             let stream = stream?;
             stream.set_nodelay(true)?;
+            self.spawn_client(stream);
+        }
+        Ok(())
+    }
+
+    /// Accept connections from clients on every address in `addrs` (see
+    /// [`Server::start_on`]), handling each one in a task on the current tokio
+    /// runtime.
+    ///
+    /// Only returns if accepting connections fails.
+    #[cfg(feature = "tokio")]
+    pub async fn start_async(self, addrs: Vec<SocketAddr>) -> Result<()> {
+        ensure!(!addrs.is_empty(), "no addresses to listen on");
+        let mut listeners = tokio::task::JoinSet::new();
+        for addr in &addrs {
+            let v6_only = addrs.iter().any(|a| a.is_ipv4() && a.port() == addr.port());
+            let listener = bind_tcp(*addr, v6_only).wrap_err_with(|| format!("binding {addr}"))?;
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            listeners.spawn(self.clone().accept_tcp_async(listener));
+        }
+        // listeners only stop on errors, so report the first one
+        listeners
+            .join_next()
+            .await
+            .expect("no listener tasks")
+            .wrap_err("listener task failed")?
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn accept_tcp_async(self, listener: tokio::net::TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            stream.set_nodelay(true)?;
             info!(target: "nbd", "client connected");
             let server = self.0.clone();
-            thread::spawn(move || match server.handle_client(stream) {
-                Ok(_) => info!(target: "nbd", "client disconnected"),
-                Err(err) => eprintln!("error handling client:\n{:?}", err),
+            tokio::spawn(async move {
+                match server.handle_client_async(stream).await {
+                    Ok(_) => info!(target: "nbd", "client disconnected"),
+                    Err(err) => eprintln!("error handling client:\n{:?}", err),
+                }
             });
         }
+    }
+
+    /// Start accepting connections from clients on a Unix socket at `path`,
+    /// and processing commands.
+    ///
+    /// The socket file must not already exist.
+    pub fn start_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)
+            .wrap_err_with(|| format!("binding Unix socket {}", path.display()))?;
+        for stream in listener.incoming() {
+            self.spawn_client(stream?);
+        }
         Ok(())
     }
+
+    /// Handle a newly connected client in a separate thread.
+    fn spawn_client<S: Duplex + 'static>(&self, stream: S) {
+        info!(target: "nbd", "client connected");
+        let server = self.0.clone();
+        thread::spawn(move || match server.handle_socket(stream) {
+            Ok(_) => info!(target: "nbd", "client disconnected"),
+            Err(err) => eprintln!("error handling client:\n{:?}", err),
+        });
+    }
+}
+
+/// Create a TCP listener on `addr`.
+///
+/// The standard library leaves IPV6_V6ONLY at the system default, which would
+/// make dual-stack listening depend on the host's configuration.
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> Result<TcpListener> {
+    use nix::sys::socket::{self, sockopt, AddressFamily, Backlog, SockFlag, SockType};
+    let family = if addr.is_ipv6() {
+        AddressFamily::Inet6
+    } else {
+        AddressFamily::Inet
+    };
+    let fd = socket::socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    socket::setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    if addr.is_ipv6() {
+        socket::setsockopt(&fd, sockopt::Ipv6V6Only, &v6_only)?;
+    }
+    socket::bind(fd.as_raw_fd(), &socket::SockaddrStorage::from(addr))?;
+    socket::listen(&fd, Backlog::MAXCONN)?;
+    Ok(TcpListener::from(fd))
 }
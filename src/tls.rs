@@ -0,0 +1,362 @@
+//! TLS support, for upgrading connections with `NBD_OPT_STARTTLS`.
+//!
+//! Both the server and the client support two ways of authenticating:
+//! X.509 certificates and pre-shared keys (PSK). TLS is implemented with
+//! OpenSSL.
+//!
+//! PSK files use the same format as `psktool` (used by nbdkit and qemu): one
+//! key per line, as `identity:hex-key`.
+#![deny(missing_docs)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use openssl::ssl::{
+    ConnectConfiguration, HandshakeError, SslAcceptor, SslConnector, SslFiletype, SslMethod,
+    SslStream, SslVerifyMode, SslVersion,
+};
+
+/// Whether clients must use TLS to access an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsPolicy {
+    /// Clients can use the export with or without TLS.
+    #[default]
+    Optional,
+    /// Clients must negotiate TLS before they can use (or get information
+    /// about) the export.
+    Required,
+}
+
+/// Parse a PSK file with lines of the form `identity:hex-key`.
+pub fn read_psk_file<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+    let path = path.as_ref();
+    let contents =
+        fs::read_to_string(path).wrap_err_with(|| format!("reading PSK file {path:?}"))?;
+    let mut keys = BTreeMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let (identity, key) = line
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| eyre!("PSK file {path:?} has a line without an identity"))?;
+        keys.insert(identity.to_string(), decode_hex(key)?);
+    }
+    Ok(keys)
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        bail!("hex key has odd length");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).wrap_err("invalid hex key"))
+        .collect()
+}
+
+/// Copy an identity into the buffer OpenSSL provides, with a NUL terminator.
+fn put_identity(identity: &str, buf: &mut [u8]) -> std::result::Result<(), ()> {
+    let identity = identity.as_bytes();
+    if identity.len() >= buf.len() {
+        return Err(());
+    }
+    buf[..identity.len()].copy_from_slice(identity);
+    buf[identity.len()] = 0;
+    Ok(())
+}
+
+/// Describe a failed handshake (HandshakeError only implements Display for
+/// streams that implement Debug).
+fn handshake_error<S>(err: HandshakeError<S>) -> color_eyre::Report {
+    match err {
+        HandshakeError::SetupFailure(err) => eyre!("TLS setup failed: {err}"),
+        HandshakeError::Failure(s) | HandshakeError::WouldBlock(s) => {
+            eyre!("TLS handshake failed: {}", s.error())
+        }
+    }
+}
+
+// PSK ciphers for TLS 1.2 (TLS 1.3 uses its default cipher suites).
+const PSK_CIPHERS: &str = "PSK";
+
+/// Server-side TLS configuration.
+#[derive(Clone)]
+pub struct ServerTls(Arc<SslAcceptor>);
+
+impl fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTls").finish_non_exhaustive()
+    }
+}
+
+impl ServerTls {
+    /// Authenticate the server with an X.509 certificate (chain) and private
+    /// key, both PEM files.
+    ///
+    /// If `ca` is given, clients must also present a certificate signed by
+    /// it.
+    pub fn x509<P: AsRef<Path>>(cert: P, key: P, ca: Option<P>) -> Result<Self> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        builder
+            .set_certificate_chain_file(cert.as_ref())
+            .wrap_err_with(|| format!("loading certificate {:?}", cert.as_ref()))?;
+        builder
+            .set_private_key_file(key.as_ref(), SslFiletype::PEM)
+            .wrap_err_with(|| format!("loading private key {:?}", key.as_ref()))?;
+        builder
+            .check_private_key()
+            .wrap_err("private key does not match certificate")?;
+        if let Some(ca) = ca {
+            builder
+                .set_ca_file(ca.as_ref())
+                .wrap_err_with(|| format!("loading CA certificate {:?}", ca.as_ref()))?;
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(Self(Arc::new(builder.build())))
+    }
+
+    /// Authenticate clients (and the server) with pre-shared keys, by
+    /// identity.
+    pub fn psk(keys: BTreeMap<String, Vec<u8>>) -> Result<Self> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        builder.set_cipher_list(PSK_CIPHERS)?;
+        builder.set_psk_server_callback(move |_ssl, identity, psk_buf| {
+            let key = identity
+                .and_then(|identity| std::str::from_utf8(identity).ok())
+                .and_then(|identity| keys.get(identity.trim_end_matches('\0')));
+            match key {
+                Some(key) if key.len() <= psk_buf.len() => {
+                    psk_buf[..key.len()].copy_from_slice(key);
+                    Ok(key.len())
+                }
+                // a zero length key fails the handshake
+                _ => Ok(0),
+            }
+        });
+        Ok(Self(Arc::new(builder.build())))
+    }
+
+    /// Authenticate with the keys in a PSK file (see [`read_psk_file`]).
+    pub fn psk_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::psk(read_psk_file(path)?)
+    }
+
+    pub(crate) fn accept<S: Read + Write>(&self, stream: S) -> Result<SslStream<S>> {
+        let stream = self.0.accept(stream).map_err(handshake_error)?;
+        Ok(stream)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn accept_async<S>(&self, stream: S) -> Result<tokio_openssl::SslStream<S>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let ssl = openssl::ssl::Ssl::new(self.0.context())?;
+        let mut stream = tokio_openssl::SslStream::new(ssl, stream)?;
+        std::pin::Pin::new(&mut stream)
+            .accept()
+            .await
+            .map_err(|err| eyre!("TLS handshake failed: {err}"))?;
+        Ok(stream)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ClientAuth {
+    X509 {
+        ca: Option<PathBuf>,
+        cert: Option<(PathBuf, PathBuf)>,
+        hostname: Option<String>,
+    },
+    Psk {
+        identity: String,
+        key: Vec<u8>,
+    },
+}
+
+/// Client-side TLS configuration.
+#[derive(Debug, Clone)]
+pub struct ClientTls(ClientAuth);
+
+impl ClientTls {
+    /// Verify the server's X.509 certificate against `ca` (a PEM file), or
+    /// against the system's trusted certificates if `ca` is `None`.
+    pub fn x509<P: AsRef<Path>>(ca: Option<P>) -> Self {
+        Self(ClientAuth::X509 {
+            ca: ca.map(|ca| ca.as_ref().to_path_buf()),
+            cert: None,
+            hostname: None,
+        })
+    }
+
+    /// Check that the server's certificate is for `hostname`.
+    ///
+    /// Without a hostname, any certificate signed by a trusted CA is
+    /// accepted. Has no effect when using PSK.
+    pub fn hostname(mut self, name: &str) -> Self {
+        if let ClientAuth::X509 { hostname, .. } = &mut self.0 {
+            *hostname = Some(name.to_string());
+        }
+        self
+    }
+
+    /// Present a client certificate and private key (both PEM files), for
+    /// servers that authenticate clients. Has no effect when using PSK.
+    pub fn client_certificate<P: AsRef<Path>>(mut self, cert_file: P, key_file: P) -> Self {
+        if let ClientAuth::X509 { cert, .. } = &mut self.0 {
+            *cert = Some((
+                cert_file.as_ref().to_path_buf(),
+                key_file.as_ref().to_path_buf(),
+            ));
+        }
+        self
+    }
+
+    /// Authenticate with a pre-shared key.
+    pub fn psk(identity: &str, key: &[u8]) -> Self {
+        Self(ClientAuth::Psk {
+            identity: identity.to_string(),
+            key: key.to_vec(),
+        })
+    }
+
+    /// Authenticate with the key for `identity` from a PSK file (see
+    /// [`read_psk_file`]).
+    pub fn psk_file<P: AsRef<Path>>(path: P, identity: &str) -> Result<Self> {
+        let keys = read_psk_file(&path)?;
+        let key = keys
+            .get(identity)
+            .ok_or_else(|| eyre!("no key for {identity} in {:?}", path.as_ref()))?;
+        Ok(Self::psk(identity, key))
+    }
+
+    /// Configure a connection, returning the hostname to check the server's
+    /// certificate against (or "" for none).
+    fn configure(&self) -> Result<(ConnectConfiguration, &str)> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        let mut hostname = None;
+        match &self.0 {
+            ClientAuth::X509 {
+                ca,
+                cert,
+                hostname: name,
+            } => {
+                if let Some(ca) = ca {
+                    builder
+                        .set_ca_file(ca)
+                        .wrap_err_with(|| format!("loading CA certificate {ca:?}"))?;
+                }
+                if let Some((cert, key)) = cert {
+                    builder
+                        .set_certificate_chain_file(cert)
+                        .wrap_err_with(|| format!("loading certificate {cert:?}"))?;
+                    builder
+                        .set_private_key_file(key, SslFiletype::PEM)
+                        .wrap_err_with(|| format!("loading private key {key:?}"))?;
+                }
+                hostname = name.as_deref();
+            }
+            ClientAuth::Psk { identity, key } => {
+                builder.set_cipher_list(PSK_CIPHERS)?;
+                let (identity, key) = (identity.clone(), key.clone());
+                builder.set_psk_client_callback(move |_ssl, _hint, identity_buf, psk_buf| {
+                    if put_identity(&identity, identity_buf).is_err() || key.len() > psk_buf.len() {
+                        return Ok(0);
+                    }
+                    psk_buf[..key.len()].copy_from_slice(&key);
+                    Ok(key.len())
+                });
+            }
+        }
+        let connector = builder.build();
+        let mut config = connector.configure()?;
+        if hostname.is_none() {
+            config = config
+                .verify_hostname(false)
+                .use_server_name_indication(false);
+        }
+        Ok((config, hostname.unwrap_or("")))
+    }
+
+    pub(crate) fn connect<S: Read + Write>(&self, stream: S) -> Result<SslStream<S>> {
+        let (config, hostname) = self.configure()?;
+        let stream = config.connect(hostname, stream).map_err(handshake_error)?;
+        Ok(stream)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn connect_async<S>(&self, stream: S) -> Result<tokio_openssl::SslStream<S>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let (config, hostname) = self.configure()?;
+        let ssl = config.into_ssl(hostname)?;
+        let mut stream = tokio_openssl::SslStream::new(ssl, stream)?;
+        std::pin::Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(|err| eyre!("TLS handshake failed: {err}"))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::Result;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    use super::*;
+
+    /// Create a self-signed certificate for localhost, returning the paths to
+    /// the certificate and private key.
+    pub(crate) fn self_signed_cert(name: &str) -> Result<(PathBuf, PathBuf)> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut subject = X509NameBuilder::new()?;
+        subject.append_entry_by_text("CN", "localhost")?;
+        let subject = subject.build();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_subject_name(&subject)?;
+        builder.set_issuer_name(&subject)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(san)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        let cert = builder.build();
+
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("nbd-{name}-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("nbd-{name}-{}.key", std::process::id()));
+        fs::write(&cert_path, cert.to_pem()?)?;
+        fs::write(&key_path, key.private_key_to_pem_pkcs8()?)?;
+        Ok((cert_path, key_path))
+    }
+
+    #[test]
+    fn test_read_psk_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-psk-{}", std::process::id()));
+        fs::write(&path, "alice:0102ff\n\nbob:00\n")?;
+        let keys = read_psk_file(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(keys["alice"], [1, 2, 255]);
+        assert_eq!(keys["bob"], [0]);
+        Ok(())
+    }
+}
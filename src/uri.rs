@@ -0,0 +1,281 @@
+//! NBD URIs, which name an export on a server.
+//!
+//! See the [NBD URI specification]. The supported forms are:
+//!
+//! - `nbd://host[:port][/export]`, and `nbds://...` for TLS
+//! - `nbd+unix:///[export]?socket=path`, and `nbds+unix://...` for TLS
+//!
+//! TLS is configured with the query parameters `tls-certificates` (a directory
+//! with `ca-cert.pem`, and optionally `client-cert.pem` and
+//! `client-key.pem`), or `tls-psk-file` and `tls-username`. The names follow
+//! libnbd. Without either, the server's certificate is checked against the
+//! system's trusted certificates.
+//!
+//! [NBD URI specification]: https://github.com/NetworkBlockDevice/nbd/blob/master/doc/uri.md
+#![deny(missing_docs)]
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, ensure, eyre, WrapErr};
+use color_eyre::{Report, Result};
+use log::warn;
+
+use crate::client::ClientOptions;
+use crate::proto::DEFAULT_PORT;
+use crate::tls::ClientTls;
+
+/// How to reach the server named by a URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A TCP connection.
+    Tcp {
+        /// Host name or IP address (IPv6 addresses are not bracketed).
+        host: String,
+        /// Port, which defaults to [`DEFAULT_PORT`].
+        port: u16,
+    },
+    /// A Unix domain socket.
+    Unix(PathBuf),
+}
+
+/// A parsed NBD URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdUri {
+    /// How to connect to the server.
+    pub transport: Transport,
+    /// Whether the connection must use TLS (the `nbds` schemes).
+    pub tls: bool,
+    /// Name of the export (empty for the server's default export).
+    pub export_name: String,
+    /// Directory with X.509 certificates (`tls-certificates`).
+    pub tls_certificates: Option<PathBuf>,
+    /// PSK file to authenticate with (`tls-psk-file`).
+    pub tls_psk_file: Option<PathBuf>,
+    /// PSK identity (`tls-username`, or the user in the authority).
+    pub tls_username: Option<String>,
+}
+
+/// Decode `%XX` escapes.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| eyre!("invalid percent-encoding in {s:?}"))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).wrap_err_with(|| format!("{s:?} is not UTF-8"))
+}
+
+/// Split `host[:port]`, where an IPv6 host is in brackets.
+fn parse_host_port(authority: &str) -> Result<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| eyre!("unterminated IPv6 address in {authority:?}"))?;
+        let port = match rest {
+            "" => None,
+            _ => Some(
+                rest.strip_prefix(':')
+                    .ok_or_else(|| eyre!("unexpected {rest:?} after IPv6 address"))?,
+            ),
+        };
+        (host, port)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    ensure!(!host.is_empty(), "missing host");
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .wrap_err_with(|| format!("invalid port {port:?}"))?,
+        None => DEFAULT_PORT,
+    };
+    Ok((percent_decode(host)?, port))
+}
+
+impl FromStr for NbdUri {
+    type Err = Report;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let (scheme, rest) = uri
+            .split_once("://")
+            .ok_or_else(|| eyre!("{uri:?} is not a URI"))?;
+        let (tls, unix) = match scheme.to_ascii_lowercase().as_str() {
+            "nbd" => (false, false),
+            "nbds" => (true, false),
+            "nbd+unix" => (false, true),
+            "nbds+unix" => (true, true),
+            _ => bail!("unsupported URI scheme {scheme:?}"),
+        };
+        let rest = rest.split_once('#').map_or(rest, |(rest, _fragment)| rest);
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        // only the first slash separates the export name
+        let export_name = percent_decode(path.strip_prefix('/').unwrap_or(path))?;
+        let (user, authority) = match authority.rsplit_once('@') {
+            Some((user, authority)) => (Some(percent_decode(user)?), authority),
+            None => (None, authority),
+        };
+
+        let mut socket = None;
+        let mut uri_opts = NbdUri {
+            transport: Transport::Unix(PathBuf::new()),
+            tls,
+            export_name,
+            tls_certificates: None,
+            tls_psk_file: None,
+            tls_username: user,
+        };
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "socket" => socket = Some(PathBuf::from(value)),
+                "tls-certificates" => uri_opts.tls_certificates = Some(PathBuf::from(value)),
+                "tls-psk-file" => uri_opts.tls_psk_file = Some(PathBuf::from(value)),
+                "tls-username" => uri_opts.tls_username = Some(value),
+                _ => warn!("ignoring unknown URI parameter {key:?}"),
+            }
+        }
+
+        uri_opts.transport = if unix {
+            ensure!(authority.is_empty(), "Unix socket URIs cannot have a host");
+            Transport::Unix(socket.ok_or_else(|| eyre!("missing socket parameter"))?)
+        } else {
+            ensure!(
+                socket.is_none(),
+                "the socket parameter requires a +unix scheme"
+            );
+            let (host, port) = parse_host_port(authority)?;
+            Transport::Tcp { host, port }
+        };
+        Ok(uri_opts)
+    }
+}
+
+impl NbdUri {
+    /// The TLS configuration requested by the URI, if it uses TLS.
+    fn client_tls(&self) -> Result<Option<ClientTls>> {
+        if !self.tls {
+            return Ok(None);
+        }
+        if let Some(psk_file) = &self.tls_psk_file {
+            let username = match &self.tls_username {
+                Some(username) => username.clone(),
+                None => std::env::var("USER").wrap_err("tls-psk-file needs tls-username")?,
+            };
+            return Ok(Some(ClientTls::psk_file(psk_file, &username)?));
+        }
+        let mut tls = match &self.tls_certificates {
+            Some(dir) => {
+                let mut tls = ClientTls::x509(Some(dir.join("ca-cert.pem")));
+                let (cert, key) = (dir.join("client-cert.pem"), dir.join("client-key.pem"));
+                if cert.exists() && key.exists() {
+                    tls = tls.client_certificate(cert, key);
+                }
+                tls
+            }
+            None => ClientTls::x509(None::<PathBuf>),
+        };
+        if let Transport::Tcp { host, .. } = &self.transport {
+            tls = tls.hostname(host);
+        }
+        Ok(Some(tls))
+    }
+
+    /// Options for connecting a [`crate::client::Client`] to the export named
+    /// by this URI.
+    pub fn client_options(&self) -> Result<ClientOptions> {
+        Ok(ClientOptions {
+            export_name: self.export_name.clone(),
+            tls: self.client_tls()?,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(uri: &str) -> NbdUri {
+        uri.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_tcp() {
+        let uri = parse("nbd://example.com");
+        assert_eq!(
+            uri.transport,
+            Transport::Tcp {
+                host: "example.com".to_string(),
+                port: DEFAULT_PORT
+            }
+        );
+        assert!(!uri.tls);
+        assert_eq!(uri.export_name, "");
+
+        let uri = parse("nbds://[::1]:10810/disk%201");
+        assert_eq!(
+            uri.transport,
+            Transport::Tcp {
+                host: "::1".to_string(),
+                port: 10810
+            }
+        );
+        assert!(uri.tls);
+        assert_eq!(uri.export_name, "disk 1");
+
+        // only the first slash is removed
+        assert_eq!(parse("nbd://localhost//disk").export_name, "/disk");
+        assert_eq!(
+            parse("nbds://alice@localhost/?tls-psk-file=keys").tls_username,
+            Some("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_unix() {
+        let uri = parse("nbd+unix:///disk?socket=/run/nbd.sock");
+        assert_eq!(uri.transport, Transport::Unix("/run/nbd.sock".into()));
+        assert_eq!(uri.export_name, "disk");
+
+        let uri = parse("nbds+unix://?socket=nbd.sock&tls-certificates=/etc/pki/nbd");
+        assert!(uri.tls);
+        assert_eq!(uri.tls_certificates, Some("/etc/pki/nbd".into()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for uri in [
+            "localhost:10809",
+            "http://localhost/",
+            "nbd+vsock://1:10809",
+            "nbd://localhost:port/",
+            "nbd://[::1/",
+            "nbd://localhost/?socket=nbd.sock",
+            "nbd+unix:///disk",
+            "nbd+unix://localhost/?socket=nbd.sock",
+            "nbd://localhost/%zz",
+        ] {
+            assert!(uri.parse::<NbdUri>().is_err(), "parsed {uri:?}");
+        }
+    }
+}
@@ -0,0 +1,644 @@
+//! An io_uring backend for exporting files and block devices.
+//!
+//! [`Uring`] sends the reads, writes, flushes, and `fallocate` calls of an
+//! export to a thread that owns an io_uring. Operations that arrive while
+//! others are in flight are submitted together, with a single system call, so
+//! the backend works best when connections have several requests in flight
+//! at once (see [`Server::set_workers`](crate::server::Server::set_workers)).
+#![deny(missing_docs)]
+
+use std::alloc::{self, Layout};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use log::error;
+
+use crate::proto::Extent;
+use crate::server::{zero_file_range, Blocks, Device};
+
+/// Settings for a [`Uring`].
+#[derive(Debug, Clone)]
+pub struct UringOptions {
+    /// Number of operations that can be in flight at once.
+    pub entries: u32,
+    /// Number of buffers to register with the kernel, which reads and writes
+    /// of up to [`UringOptions::buffer_size`] bytes go through.
+    ///
+    /// Registered buffers save the kernel from mapping memory for every
+    /// operation, at the cost of a copy, which pays off for files opened
+    /// with `O_DIRECT`. Operations use their own memory when every buffer is
+    /// taken.
+    pub registered_buffers: u16,
+    /// Size of each registered buffer, in bytes.
+    pub buffer_size: usize,
+}
+
+impl Default for UringOptions {
+    fn default() -> Self {
+        Self {
+            entries: 128,
+            registered_buffers: 0,
+            buffer_size: 128 * 1024,
+        }
+    }
+}
+
+/// An operation for the ring thread, and where to send its result.
+struct Op {
+    entry: squeue::Entry,
+    done: mpsc::SyncSender<i32>,
+    // set by whichever of the thread (submitting the entry) and the caller
+    // (giving up on it) gets there first
+    claimed: Arc<AtomicBool>,
+}
+
+/// A thread that carries out operations with an io_uring.
+struct Ring {
+    ops: Option<mpsc::Sender<Op>>,
+    // an eventfd, which wakes up the thread when there are new operations
+    wake: File,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ring {
+    fn new(ring: IoUring) -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let wake = unsafe { File::from_raw_fd(fd) };
+        let (ops, rx) = mpsc::channel();
+        let thread_wake = wake.try_clone()?;
+        let thread = thread::Builder::new()
+            .name("nbd-io-uring".to_string())
+            .spawn(move || Driver::new(ring).run(rx, thread_wake))?;
+        Ok(Self {
+            ops: Some(ops),
+            wake,
+            thread: Some(thread),
+        })
+    }
+
+    fn wake(wake: &File) -> io::Result<()> {
+        (&*wake).write_all(&1u64.to_ne_bytes())
+    }
+
+    /// Carry out an operation, returning its result.
+    ///
+    /// # Safety
+    ///
+    /// Any memory the entry refers to must remain valid until this returns.
+    unsafe fn run(&self, entry: squeue::Entry) -> io::Result<u32> {
+        let (done, result) = mpsc::sync_channel(1);
+        let claimed = Arc::new(AtomicBool::new(false));
+        let stopped = || io::Error::other("io_uring thread stopped");
+        let ops = self.ops.as_ref().expect("ring is running");
+        let op = Op {
+            entry,
+            done,
+            claimed: Arc::clone(&claimed),
+        };
+        ops.send(op).map_err(|_| stopped())?;
+        if let Err(err) = Self::wake(&self.wake) {
+            // the entry can only be dropped if the thread has not submitted
+            // it, in which case it is awake and the result will come
+            if !claimed.swap(true, Ordering::AcqRel) {
+                return Err(err);
+            }
+        }
+        let result = result.recv().map_err(|_| stopped())?;
+        if result < 0 {
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        Ok(result as u32)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        self.ops = None;
+        // if the thread cannot be woken, it stops the next time it wakes up,
+        // with nothing in flight but its own read of the eventfd
+        if Self::wake(&self.wake).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// The io_uring of the ring thread, and the operations in flight on it.
+struct Driver {
+    ring: IoUring,
+    // the read of the eventfd has user data 0, and operations count up from 1
+    next_id: u64,
+    waiting: BTreeMap<u64, mpsc::SyncSender<i32>>,
+    wake_armed: bool,
+}
+
+impl Driver {
+    fn new(ring: IoUring) -> Self {
+        Self {
+            ring,
+            next_id: 1,
+            waiting: BTreeMap::new(),
+            wake_armed: false,
+        }
+    }
+
+    /// Submit operations as they arrive and send back their results, until
+    /// the sending side is dropped.
+    fn run(mut self, ops: mpsc::Receiver<Op>, wake: File) {
+        let mut wake_buf = [0u8; 8];
+        // leave room for the read of the eventfd
+        let capacity = self.ring.params().sq_entries() as usize - 1;
+        let mut closed = false;
+        loop {
+            if !self.wake_armed && !closed {
+                let entry = opcode::Read::new(
+                    types::Fd(wake.as_raw_fd()),
+                    wake_buf.as_mut_ptr(),
+                    wake_buf.len() as u32,
+                )
+                .build()
+                .user_data(0);
+                // the buffer outlives the read, since the loop only ends
+                // after it completes (if it cannot be queued, the next
+                // iteration tries again)
+                self.wake_armed = unsafe { self.push(&entry) }.is_ok();
+            }
+            while !closed && self.waiting.len() < capacity {
+                match ops.try_recv() {
+                    Ok(op) => {
+                        if op.claimed.swap(true, Ordering::AcqRel) {
+                            // the caller has given up on the operation
+                            continue;
+                        }
+                        let entry = op.entry.user_data(self.next_id);
+                        match unsafe { self.push(&entry) } {
+                            Ok(()) => {
+                                self.waiting.insert(self.next_id, op.done);
+                                self.next_id += 1;
+                            }
+                            Err(err) => {
+                                let _ = op.done.send(-err.raw_os_error().unwrap_or(libc::EIO));
+                            }
+                        }
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => closed = true,
+                }
+            }
+            if closed && self.waiting.is_empty() {
+                if !self.wake_armed {
+                    return;
+                }
+                // finish the read of the eventfd before its buffer goes away
+                if let Err(err) = Ring::wake(&wake) {
+                    abort("waking the io_uring thread", err);
+                }
+            }
+            let want = usize::from(self.wake_armed || !self.waiting.is_empty());
+            if let Err(err) = self.submit(want) {
+                abort("io_uring submission", err);
+            }
+        }
+    }
+
+    /// Queue an entry, submitting the queue and handling completions to make
+    /// room if it is full.
+    ///
+    /// # Safety
+    ///
+    /// Any memory the entry refers to must remain valid until it completes.
+    unsafe fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        if self.ring.submission().push(entry).is_ok() {
+            return Ok(());
+        }
+        self.submit(0)?;
+        self.ring
+            .submission()
+            .push(entry)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))
+    }
+
+    /// Submit the queued entries, wait for `want` completions, and send back
+    /// the results of every completed operation.
+    fn submit(&mut self, want: usize) -> io::Result<()> {
+        match self.ring.submit_and_wait(want) {
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+                ) => {}
+            Err(err) => return Err(err),
+        }
+        for cqe in self.ring.completion() {
+            match cqe.user_data() {
+                0 => self.wake_armed = false,
+                id => {
+                    if let Some(done) = self.waiting.remove(&id) {
+                        let _ = done.send(cqe.result());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stop the process after the ring thread fails with operations in flight.
+///
+/// The kernel may still use the memory of those operations, so their callers
+/// cannot be allowed to continue, and the thread cannot unwind.
+fn abort(what: &str, err: io::Error) -> ! {
+    error!("{what} failed: {err}");
+    std::process::abort()
+}
+
+/// Memory registered with the kernel as fixed buffers, which are lent out one
+/// at a time.
+struct Buffers {
+    memory: Option<NonNull<u8>>,
+    layout: Layout,
+    size: usize,
+    // indices of the buffers that are not lent out
+    free: Mutex<Vec<u16>>,
+}
+
+// The memory is only accessed through a lent buffer, which has exclusive use
+// of its part.
+unsafe impl Send for Buffers {}
+unsafe impl Sync for Buffers {}
+
+impl Buffers {
+    fn new(count: u16, size: usize) -> io::Result<Self> {
+        // aligned for O_DIRECT
+        let layout = Layout::from_size_align(count as usize * size, 4096)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let memory = if layout.size() == 0 {
+            None
+        } else {
+            let memory = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+                .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+            Some(memory)
+        };
+        Ok(Self {
+            memory,
+            layout,
+            size,
+            free: Mutex::new((0..count).rev().collect()),
+        })
+    }
+
+    fn iovecs(&self) -> Vec<libc::iovec> {
+        let Some(memory) = self.memory else {
+            return vec![];
+        };
+        (0..self.layout.size() / self.size)
+            .map(|i| libc::iovec {
+                iov_base: unsafe { memory.as_ptr().add(i * self.size) }.cast(),
+                iov_len: self.size,
+            })
+            .collect()
+    }
+
+    /// Borrow a buffer for `len` bytes, if one is free and large enough.
+    fn lend(&self, len: usize) -> Option<Lent<'_>> {
+        if len > self.size {
+            return None;
+        }
+        let index = self.free.lock().unwrap().pop()?;
+        Some(Lent {
+            buffers: self,
+            index,
+        })
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        if let Some(memory) = self.memory {
+            unsafe { alloc::dealloc(memory.as_ptr(), self.layout) };
+        }
+    }
+}
+
+/// A registered buffer, which goes back to the pool when dropped.
+struct Lent<'a> {
+    buffers: &'a Buffers,
+    index: u16,
+}
+
+impl Lent<'_> {
+    fn as_mut_ptr(&self) -> *mut u8 {
+        let memory = self.buffers.memory.expect("lent buffers exist");
+        unsafe { memory.as_ptr().add(self.index as usize * self.buffers.size) }
+    }
+
+    fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), len) }
+    }
+}
+
+impl Drop for Lent<'_> {
+    fn drop(&mut self) {
+        self.buffers.free.lock().unwrap().push(self.index);
+    }
+}
+
+#[derive(Debug)]
+enum Backing {
+    File(File),
+    Device(Device),
+}
+
+/// Blocks for a file or block device that do their I/O with io_uring.
+///
+/// Zeroing and discarding ranges of a block device still use `ioctl`s, and
+/// other operations such as [`Blocks::extents`] use ordinary system calls.
+pub struct Uring {
+    // dropped first, so that nothing is in flight when the buffers are freed
+    ring: Ring,
+    buffers: Buffers,
+    backing: Backing,
+    fd: RawFd,
+}
+
+impl fmt::Debug for Uring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Uring")
+            .field("backing", &self.backing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Uring {
+    /// Do the I/O for a file with io_uring.
+    pub fn file(file: File, opts: &UringOptions) -> io::Result<Self> {
+        Self::new(Backing::File(file), opts)
+    }
+
+    /// Do the I/O for a block device with io_uring.
+    pub fn device(device: Device, opts: &UringOptions) -> io::Result<Self> {
+        Self::new(Backing::Device(device), opts)
+    }
+
+    fn new(backing: Backing, opts: &UringOptions) -> io::Result<Self> {
+        let fd = match &backing {
+            Backing::File(file) => file.as_raw_fd(),
+            Backing::Device(device) => device.as_raw_fd(),
+        };
+        let ring = IoUring::new(opts.entries.max(2))?;
+        let buffers = Buffers::new(opts.registered_buffers, opts.buffer_size)?;
+        let iovecs = buffers.iovecs();
+        if !iovecs.is_empty() {
+            // the buffers are freed only after the ring
+            unsafe { ring.submitter().register_buffers(&iovecs)? };
+        }
+        Ok(Self {
+            ring: Ring::new(ring)?,
+            buffers,
+            backing,
+            fd,
+        })
+    }
+
+    fn blocks(&self) -> &dyn Blocks {
+        match &self.backing {
+            Backing::File(file) => file,
+            Backing::Device(device) => device,
+        }
+    }
+
+    /// Read `len` bytes into memory at `buf` (a registered buffer if `fixed`
+    /// is given).
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for writing `len` bytes.
+    unsafe fn read_exact(
+        &self,
+        mut buf: *mut u8,
+        mut len: usize,
+        mut off: u64,
+        fixed: Option<u16>,
+    ) -> io::Result<()> {
+        let fd = types::Fd(self.fd);
+        while len > 0 {
+            let n = len.min(u32::MAX as usize) as u32;
+            let entry = match fixed {
+                Some(index) => opcode::ReadFixed::new(fd, buf, n, index)
+                    .offset(off)
+                    .build(),
+                None => opcode::Read::new(fd, buf, n).offset(off).build(),
+            };
+            let n = self.ring.run(entry)? as usize;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            buf = buf.add(n);
+            len -= n;
+            off += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Write `len` bytes from memory at `buf` (a registered buffer if `fixed`
+    /// is given).
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for reading `len` bytes.
+    unsafe fn write_all(
+        &self,
+        mut buf: *const u8,
+        mut len: usize,
+        mut off: u64,
+        fixed: Option<u16>,
+    ) -> io::Result<()> {
+        let fd = types::Fd(self.fd);
+        while len > 0 {
+            let n = len.min(u32::MAX as usize) as u32;
+            let entry = match fixed {
+                Some(index) => opcode::WriteFixed::new(fd, buf, n, index)
+                    .offset(off)
+                    .build(),
+                None => opcode::Write::new(fd, buf, n).offset(off).build(),
+            };
+            let n = self.ring.run(entry)? as usize;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            buf = buf.add(n);
+            len -= n;
+            off += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Call `fallocate`, returning false if the file does not support `mode`.
+    fn fallocate(&self, mode: libc::c_int, off: u64, len: u64) -> io::Result<bool> {
+        let entry = opcode::Fallocate::new(types::Fd(self.fd), len)
+            .offset(off)
+            .mode(mode)
+            .build();
+        match unsafe { self.ring.run(entry) } {
+            Ok(_) => Ok(true),
+            Err(err) => match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+impl Blocks for Uring {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        match self.buffers.lend(buf.len()) {
+            Some(mut lent) => {
+                unsafe { self.read_exact(lent.as_mut_ptr(), buf.len(), off, Some(lent.index))? };
+                buf.copy_from_slice(lent.as_mut_slice(buf.len()));
+                Ok(())
+            }
+            None => unsafe { self.read_exact(buf.as_mut_ptr(), buf.len(), off, None) },
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        match self.buffers.lend(buf.len()) {
+            Some(mut lent) => {
+                lent.as_mut_slice(buf.len()).copy_from_slice(buf);
+                unsafe { self.write_all(lent.as_mut_ptr(), buf.len(), off, Some(lent.index)) }
+            }
+            None => unsafe { self.write_all(buf.as_ptr(), buf.len(), off, None) },
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.blocks().size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let entry = opcode::Fsync::new(types::Fd(self.fd)).build();
+        unsafe { self.ring.run(entry) }?;
+        Ok(())
+    }
+
+    fn extents(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.blocks().extents(off, len)
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, punch_hole: bool, fast: bool) -> io::Result<()> {
+        match &self.backing {
+            Backing::File(_) => {
+                let fallocate = |mode, off, len| self.fallocate(mode, off, len);
+                zero_file_range(self, fallocate, off, len, punch_hole, fast)
+            }
+            Backing::Device(device) => device.write_zeroes(off, len, punch_hole, fast),
+        }
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        match &self.backing {
+            Backing::File(_) => {
+                // if the file system cannot punch holes, there is nothing to do
+                let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+                self.fallocate(mode, off, len)?;
+                Ok(())
+            }
+            Backing::Device(device) => device.trim(off, len),
+        }
+    }
+
+    fn prefetch(&self, off: u64, len: u64) -> io::Result<()> {
+        self.blocks().prefetch(off, len)
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.blocks().resize(size)
+    }
+
+    fn can_resize(&self) -> bool {
+        self.blocks().can_resize()
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        // fsync covers all writes, like for the file or device itself
+        self.blocks().can_multi_conn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::testing::TempFile;
+
+    #[test]
+    fn test_uring_file() -> Result<()> {
+        // including the smallest ring, with room for one operation at a time
+        for (entries, registered_buffers) in [(8, 0), (8, 2), (2, 0)] {
+            let opts = UringOptions {
+                entries,
+                registered_buffers,
+                buffer_size: 4096,
+            };
+            let file = TempFile::new("uring")?;
+            file.set_len(64 * 1024)?;
+            let blocks = Arc::new(Uring::file(file.try_clone()?, &opts)?);
+            assert_eq!(blocks.size()?, 64 * 1024);
+            // more threads than entries and buffers, with reads and writes of
+            // both sizes
+            let threads: Vec<_> = (0..16u8)
+                .map(|i| {
+                    let blocks = blocks.clone();
+                    thread::spawn(move || -> io::Result<()> {
+                        let off = i as u64 * 4096;
+                        let len = if i % 2 == 0 { 4096 } else { 1000 };
+                        blocks.write_at(&vec![i + 1; len], off)?;
+                        let mut buf = vec![0; len];
+                        blocks.read_at(&mut buf, off)?;
+                        assert_eq!(buf, vec![i + 1; len]);
+                        Ok(())
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap()?;
+            }
+            blocks.flush()?;
+
+            blocks.write_zeroes(4096 + 10, 100, false, false)?;
+            blocks.trim(0, 4096)?;
+            let mut buf = vec![1; 4096];
+            blocks.read_at(&mut buf, 4096)?;
+            assert_eq!(&buf[..10], [2; 10]);
+            assert_eq!(&buf[10..110], [0; 100]);
+            assert_eq!(&buf[110..1000], [2; 890]);
+
+            let mut buf = [0; 10];
+            let err = blocks.read_at(&mut buf, 64 * 1024 - 5).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        Ok(())
+    }
+}
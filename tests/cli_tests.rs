@@ -96,6 +96,17 @@ fn test_server_help_flag() {
     assert!(stdout.contains("server"));
 }
 
+#[test]
+fn test_server_bad_bind_address() {
+    let out = Command::new(exe_path("server"))
+        .args(["--bind", "localhost:10809", "memory"])
+        .output()
+        .expect("failed to run server");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).expect("non utf-8 output");
+    assert!(stderr.contains("not an IP address"));
+}
+
 fn use_dev(path: &str) -> Result<()> {
     let f = OpenOptions::new().read(true).write(true).open(path)?;
 